@@ -0,0 +1,323 @@
+/*
+Assembles the mnemonics printed by the disassembler back into a ROM.
+
+    ; comments start with ';'
+    main:
+        LD V0, 0x05
+        CALL draw
+        JP main
+    draw:
+        DRW V0, V1, 5
+        RET
+    sprite:
+        db 0xf0, 0x90, 0xf0
+
+Labels can be used wherever an address is expected. Disassembler listings are
+accepted too, the leading address and opcode columns are skipped and the
+opcode column is used for instructions shown as ???.
+*/
+
+use std::collections::HashMap;
+
+use crate::constants::*;
+
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    // Address, source line and label of each instruction, for a source map
+    lines: Vec<(usize, usize, Option<String>)>,
+}
+
+impl Assembly {
+    // In the format read by debugger::source_map
+    pub fn source_map(&self, file: &str) -> String {
+        let mut out = String::new();
+        for (addr, line, label) in &self.lines {
+            out.push_str(&format!("{:#05x} {}:{}", addr, file, line));
+            if let Some(label) = label {
+                out.push(' ');
+                out.push_str(label);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+struct Statement<'a> {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+    // From the opcode column of a disassembler listing
+    opcode: Option<u16>,
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, String> {
+    // First pass finds where each label points
+    let mut labels = HashMap::new();
+    let mut statements = Vec::new();
+    let mut pending_label = None;
+    let mut addr = PROGRAM_START;
+
+    for (number, text) in source.lines().enumerate() {
+        let line = number + 1;
+        let mut text = text.split(';').next().unwrap_or("").trim();
+
+        while let Some((label, rest)) = text.split_once(':').filter(|(l, _)| is_label(l)) {
+            if labels.insert(label.to_string(), addr).is_some() {
+                return Err(format!("Line {}: label '{}' is defined twice", line, label));
+            }
+            pending_label = Some(label.to_string());
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let statement = statement(line, text)?;
+        addr += if statement.mnemonic == "DB" {
+            statement.operands.len()
+        } else {
+            2
+        };
+        statements.push((statement, pending_label.take()));
+    }
+
+    let mut assembly = Assembly {
+        bytes: Vec::new(),
+        lines: Vec::new(),
+    };
+    for (statement, label) in statements {
+        let addr = PROGRAM_START + assembly.bytes.len();
+        let at_line = |e: String| format!("Line {}: {}", statement.line, e);
+
+        if statement.mnemonic == "DB" {
+            for operand in &statement.operands {
+                let byte = number(operand, 0xFF).map_err(at_line)?;
+                assembly.bytes.push(byte as u8);
+            }
+            continue;
+        }
+
+        let opcode = encode(&statement, &labels).map_err(at_line)?;
+        assembly.bytes.extend_from_slice(&opcode.to_be_bytes());
+        assembly.lines.push((addr, statement.line, label));
+    }
+
+    if assembly.bytes.len() > MEM_SIZE - PROGRAM_START {
+        return Err(format!(
+            "Program is {} bytes, more than the {} that fit in memory",
+            assembly.bytes.len(),
+            MEM_SIZE - PROGRAM_START
+        ));
+    }
+    Ok(assembly)
+}
+
+fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn statement(line: usize, text: &str) -> Result<Statement<'_>, String> {
+    let mut text = text;
+    let mut opcode = None;
+
+    // Address and opcode columns of a disassembler listing
+    if let Some((addr, rest)) = text.split_once(char::is_whitespace) {
+        if addr.starts_with("0x") && addr.len() == 5 {
+            text = rest.trim_start();
+            if let Some((column, rest)) = text.split_once(char::is_whitespace) {
+                if column.len() == 4 && column.chars().all(|c| c.is_ascii_hexdigit()) {
+                    opcode = u16::from_str_radix(column, 16).ok();
+                    text = rest.trim_start();
+                }
+            }
+        }
+    }
+
+    let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let operands = operands
+        .split(',')
+        .map(str::trim)
+        .filter(|operand| !operand.is_empty())
+        .collect();
+
+    Ok(Statement {
+        line,
+        mnemonic: mnemonic.to_uppercase(),
+        operands,
+        opcode,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    V(u16),
+    I,
+    IndirectI,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    Bcd,
+    Value(u16),
+}
+
+fn operand(text: &str, labels: &HashMap<String, usize>) -> Result<Operand, String> {
+    let upper = text.to_uppercase();
+    Ok(match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::DelayTimer,
+        "ST" => Operand::SoundTimer,
+        "K" => Operand::Key,
+        "F" => Operand::Font,
+        "B" => Operand::Bcd,
+        _ if upper.len() == 2 && upper.starts_with('V') => {
+            Operand::V(u16::from_str_radix(&upper[1..], 16).map_err(|_| bad_operand(text))?)
+        }
+        _ => match labels.get(text) {
+            Some(addr) => Operand::Value(*addr as u16),
+            None => Operand::Value(number(text, 0xFFF)?),
+        },
+    })
+}
+
+fn number(text: &str, max: u16) -> Result<u16, String> {
+    let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("#")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| bad_operand(text))?;
+
+    if value > max {
+        return Err(format!(
+            "{} doesn't fit in {} bits",
+            text,
+            16 - max.leading_zeros()
+        ));
+    }
+    Ok(value)
+}
+
+fn bad_operand(text: &str) -> String {
+    format!("invalid operand '{}'", text)
+}
+
+fn encode(statement: &Statement, labels: &HashMap<String, usize>) -> Result<u16, String> {
+    use Operand::*;
+
+    let operands = statement
+        .operands
+        .iter()
+        .map(|text| operand(text, labels))
+        .collect::<Result<Vec<_>, _>>()?;
+    let byte = |value: u16| {
+        if value > 0xFF {
+            Err(format!("{:#x} doesn't fit in a byte", value))
+        } else {
+            Ok(value)
+        }
+    };
+
+    let opcode = match (statement.mnemonic.as_str(), operands.as_slice()) {
+        ("???", _) => statement
+            .opcode
+            .ok_or("unknown instruction without an opcode column")?,
+        ("CLS", []) => 0x00E0,
+        ("RET", []) => 0x00EE,
+        ("LOW", []) => 0x00FE,
+        ("HIGH", []) => 0x00FF,
+        ("AUDIO", []) => 0xF002,
+        ("SYS", [Value(nnn)]) => *nnn,
+        ("JP", [Value(nnn)]) => 0x1000 | nnn,
+        ("JP", [V(0), Value(nnn)]) => 0xB000 | nnn,
+        ("CALL", [Value(nnn)]) => 0x2000 | nnn,
+        ("SE", [V(x), Value(nn)]) => 0x3000 | x << 8 | byte(*nn)?,
+        ("SNE", [V(x), Value(nn)]) => 0x4000 | x << 8 | byte(*nn)?,
+        ("SE", [V(x), V(y)]) => 0x5000 | x << 8 | y << 4,
+        ("LD", [V(x), Value(nn)]) => 0x6000 | x << 8 | byte(*nn)?,
+        ("ADD", [V(x), Value(nn)]) => 0x7000 | x << 8 | byte(*nn)?,
+        ("LD", [V(x), V(y)]) => 0x8000 | x << 8 | y << 4,
+        ("OR", [V(x), V(y)]) => 0x8001 | x << 8 | y << 4,
+        ("AND", [V(x), V(y)]) => 0x8002 | x << 8 | y << 4,
+        ("XOR", [V(x), V(y)]) => 0x8003 | x << 8 | y << 4,
+        ("ADD", [V(x), V(y)]) => 0x8004 | x << 8 | y << 4,
+        ("SUB", [V(x), V(y)]) => 0x8005 | x << 8 | y << 4,
+        ("SHR", [V(x), V(y)]) => 0x8006 | x << 8 | y << 4,
+        ("SUBN", [V(x), V(y)]) => 0x8007 | x << 8 | y << 4,
+        ("SHL", [V(x), V(y)]) => 0x800E | x << 8 | y << 4,
+        ("SNE", [V(x), V(y)]) => 0x9000 | x << 8 | y << 4,
+        ("LD", [I, Value(nnn)]) => 0xA000 | nnn,
+        ("RND", [V(x), Value(nn)]) => 0xC000 | x << 8 | byte(*nn)?,
+        ("DRW", [V(x), V(y), Value(n)]) if *n < 16 => 0xD000 | x << 8 | y << 4 | n,
+        ("SKP", [V(x)]) => 0xE09E | x << 8,
+        ("SKNP", [V(x)]) => 0xE0A1 | x << 8,
+        ("PLANE", [Value(n)]) if *n < 16 => 0xF001 | n << 8,
+        ("LD", [V(x), DelayTimer]) => 0xF007 | x << 8,
+        ("LD", [V(x), Key]) => 0xF00A | x << 8,
+        ("LD", [DelayTimer, V(x)]) => 0xF015 | x << 8,
+        ("LD", [SoundTimer, V(x)]) => 0xF018 | x << 8,
+        ("ADD", [I, V(x)]) => 0xF01E | x << 8,
+        ("LD", [Font, V(x)]) => 0xF029 | x << 8,
+        ("LD", [Bcd, V(x)]) => 0xF033 | x << 8,
+        ("PITCH", [V(x)]) => 0xF03A | x << 8,
+        ("LD", [IndirectI, V(x)]) => 0xF055 | x << 8,
+        ("LD", [V(x), IndirectI]) => 0xF065 | x << 8,
+        _ => {
+            return Err(format!(
+                "invalid instruction '{} {}'",
+                statement.mnemonic,
+                statement.operands.join(", ")
+            ))
+        }
+    };
+    Ok(opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::disassembler::disassemble;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            ; draw a sprite forever
+            main: LD I, sprite
+                  DRW V0, V1, 3
+            loop: JP loop
+            sprite:
+                  db 0xf0, 0x90, 0xf0
+        ";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![0xA2, 0x06, 0xD0, 0x13, 0x12, 0x04, 0xF0, 0x90, 0xF0]
+        );
+        assert_eq!(
+            assembly.source_map("game.asm"),
+            "0x200 game.asm:3 main\n0x202 game.asm:4\n0x204 game.asm:5 loop\n"
+        );
+
+        assert!(assemble("JP nowhere").is_err());
+        assert!(assemble("LD V0, 0x100").is_err());
+        assert!(assemble("a:\na:").is_err());
+    }
+
+    #[test]
+    fn test_listing_round_trip() {
+        let program = [
+            0x00, 0xE0, 0x6A, 0x12, 0x8A, 0xB4, 0xF3, 0x55, 0xF2, 0x3A, 0xB3, 0x00, 0xF3, 0xFF,
+            0xE1, 0x9E, 0xFF,
+        ];
+        let mut memory = vec![0; PROGRAM_START];
+        memory.extend_from_slice(&program);
+
+        let listing = disassemble(&memory, PROGRAM_START, memory.len(), None);
+        assert_eq!(assemble(&listing).unwrap().bytes, program);
+    }
+}
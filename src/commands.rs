@@ -0,0 +1,241 @@
+/*
+Subcommands that work on ROMs without running them.
+*/
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assembler;
+use crate::config::Platform;
+use crate::constants::*;
+use crate::database::{Database, Metadata};
+use crate::debugger::disassembler::{self, reachable_instructions};
+use crate::debugger::profiler::opcode_class;
+use crate::drivers::rom_driver::{Program, ProgramType};
+
+pub fn disassemble(rom: &str, output: Option<&str>) -> Result<(), String> {
+    let program = Program::load(ProgramType::Path(rom.to_string())).map_err(|e| e.to_string())?;
+
+    let mut memory = vec![0; PROGRAM_START];
+    memory.extend_from_slice(&program.bytes);
+    let listing = disassembler::disassemble(&memory, PROGRAM_START, memory.len(), None);
+
+    match output {
+        Some(path) => fs::write(path, listing).map_err(|e| format!("{}: {}", path, e)),
+        None => {
+            print!("{}", listing);
+            Ok(())
+        }
+    }
+}
+
+pub fn assemble(
+    source: &str,
+    output: Option<&str>,
+    source_map: Option<&str>,
+) -> Result<(), String> {
+    let text = fs::read_to_string(source).map_err(|e| format!("{}: {}", source, e))?;
+    let assembly = assembler::assemble(&text).map_err(|e| format!("{}: {}", source, e))?;
+
+    let output = output.map_or_else(
+        || {
+            Path::new(source)
+                .with_extension("ch8")
+                .to_string_lossy()
+                .into_owned()
+        },
+        str::to_string,
+    );
+    fs::write(&output, &assembly.bytes).map_err(|e| format!("{}: {}", output, e))?;
+    println!("Wrote {} bytes to {}", assembly.bytes.len(), output);
+
+    if let Some(path) = source_map {
+        let file = map_source_path(source, path);
+        fs::write(path, assembly.source_map(&file)).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+// Source maps name their source files relative to the map's own directory
+fn map_source_path(source: &str, map: &str) -> String {
+    let map_dir = match Path::new(map).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(source), fs::canonicalize(map_dir)) {
+        (Ok(source), Ok(dir)) => relative_to(&source, &dir).to_string_lossy().into_owned(),
+        _ => source.to_string(),
+    }
+}
+
+// `path` as seen from `dir`, both absolute. Paths without a common root, like
+// on different Windows drives, are left absolute
+fn relative_to(path: &Path, dir: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path.to_path_buf();
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in dir.components().skip(common) {
+        relative.push("..");
+    }
+    relative.extend(path.components().skip(common));
+    relative
+}
+
+pub fn inspect(rom: &str) -> Result<(), String> {
+    let program = Program::load(ProgramType::Path(rom.to_string())).map_err(|e| e.to_string())?;
+    let metadata = program.metadata(&Database::load()?);
+    print!(
+        "{}",
+        report(&program, metadata.as_ref(), Some(Path::new(rom)))
+    );
+    Ok(())
+}
+
+fn report(program: &Program, metadata: Option<&Metadata>, path: Option<&Path>) -> String {
+    let instructions = reachable_instructions(&program.bytes);
+    let mut classes = BTreeMap::new();
+    for (_, opcode) in &instructions {
+        *classes.entry(opcode_class(*opcode)).or_insert(0) += 1;
+    }
+
+    let mut out = format!("Name:         {}\n", program.name);
+    out.push_str(&format!("SHA-1:        {}\n", program.sha1()));
+    if let Some(metadata) = metadata {
+        out.push_str(&format!("Title:        {}\n", metadata.title));
+        if !metadata.authors.is_empty() {
+            out.push_str(&format!("Authors:      {}\n", metadata.authors.join(", ")));
+        }
+    }
+    out.push_str(&format!(
+        "Size:         {} bytes of {} available\n",
+        program.bytes.len(),
+        MEM_SIZE - PROGRAM_START
+    ));
+    // Picked in the same order as when running, without settings files
+    let extension = path.and_then(Platform::from_extension);
+    let (platform, source) = match (metadata.and_then(|metadata| metadata.platform), extension) {
+        (Some(platform), _) => (platform, "from the ROM database".to_string()),
+        (None, Some(platform)) => (platform, "from the file extension".to_string()),
+        (None, None) => {
+            let platform = Platform::default();
+            let guess = Platform::guess(&program.bytes);
+            let source = if guess == platform {
+                "default".to_string()
+            } else {
+                format!(
+                    "default, the instructions used suggest {}, see --platform",
+                    guess.name()
+                )
+            };
+            (platform, source)
+        }
+    };
+    out.push_str(&format!("Platform:     {} ({})\n", platform.name(), source));
+    if let Some(metadata) = metadata.filter(|metadata| !metadata.keys.is_empty()) {
+        let keys: Vec<String> = metadata
+            .keys
+            .iter()
+            .map(|(action, keypad)| format!("{} = {:X}", action, keypad))
+            .collect();
+        out.push_str(&format!("Keys:         {}\n", keys.join(", ")));
+    }
+    out.push_str(&format!(
+        "Code:         {} reachable instructions\n",
+        instructions.len()
+    ));
+    let classes: Vec<String> = classes
+        .iter()
+        .map(|(class, count)| format!("{} x{}", class, count))
+        .collect();
+    out.push_str(&format!("Instructions: {}\n", classes.join(", ")));
+
+    match program.check(platform) {
+        Ok(warnings) => {
+            for warning in warnings {
+                out.push_str(&format!("Warning:      {}\n", warning));
+            }
+        }
+        Err(e) => out.push_str(&format!("Error:        {}\n", e)),
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::source_map::SourceMap;
+
+    #[test]
+    fn test_source_map_paths() {
+        assert_eq!(
+            relative_to(Path::new("/src/game.8o"), Path::new("/src")),
+            Path::new("game.8o")
+        );
+        assert_eq!(
+            relative_to(Path::new("/src/lib/a.8o"), Path::new("/build/maps")),
+            Path::new("../../src/lib/a.8o")
+        );
+
+        let dir = std::env::temp_dir().join("commands_test_source_map");
+        fs::create_dir_all(dir.join("build")).unwrap();
+        let source = dir.join("game.8o");
+        fs::write(&source, "CLS\n").unwrap();
+        let map = dir.join("build").join("game.map");
+        let map = map.to_str().unwrap();
+
+        assemble(
+            source.to_str().unwrap(),
+            Some(dir.join("build").join("game.ch8").to_str().unwrap()),
+            Some(map),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(map).unwrap(), "0x200 ../game.8o:1\n");
+        let location = SourceMap::load(map)
+            .unwrap()
+            .location(0x200)
+            .cloned()
+            .unwrap();
+        assert!(Path::new(&location.file).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_report() {
+        let program = Program {
+            bytes: vec![0x00, 0xE0, 0x00, 0xFF, 0x12, 0x06, 0x12, 0x06],
+            name: "demo".to_string(),
+        };
+        assert_eq!(
+            report(&program, None, Some(Path::new("demo.ch8"))),
+            "Name:         demo\n\
+             SHA-1:        09f200aee86eafb29550254598fc21708bd86315\n\
+             Size:         8 bytes of 3584 available\n\
+             Platform:     chip8 (from the file extension)\n\
+             Code:         4 reachable instructions\n\
+             Instructions: 00E0 x1, 00FF x1, 1NNN x2\n"
+        );
+
+        let metadata = Metadata {
+            title: "Demo".to_string(),
+            platform: Some(Platform::XoChip),
+            ..Metadata::default()
+        };
+        assert!(report(&program, None, None).contains(
+            "Platform:     chip8 (default, the instructions used suggest schip, see --platform)\n"
+        ));
+        assert!(report(&program, None, Some(Path::new("demo.SC8")))
+            .contains("Platform:     schip (from the file extension)\n"));
+
+        let report = report(&program, Some(&metadata), Some(Path::new("demo.ch8")));
+        assert!(report.contains("Title:        Demo\n"));
+        assert!(report.contains("Platform:     xochip (from the ROM database)\n"));
+    }
+}
@@ -4,7 +4,12 @@ Used to specify behaviour for specific functionns.
 */
 
 use std::ops::BitOr;
+use std::path::Path;
 
+use crate::constants::{CLOCK_SPEED, MEM_SIZE, PROGRAM_START};
+use crate::debugger::disassembler::reachable_instructions;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ConfigFlags {
     Shift = 0b1000_0000,
     JumpWithOffset = 0b0100_0000,
@@ -26,7 +31,32 @@ impl BitOr<ConfigFlags> for u8 {
     }
 }
 
-#[derive(Default)]
+// Quirk names used on the command line
+pub const QUIRKS: [(&str, ConfigFlags); 4] = [
+    ("shift", ConfigFlags::Shift),
+    ("jump", ConfigFlags::JumpWithOffset),
+    ("load-store", ConfigFlags::StoreLoadMem),
+    ("index-overflow", ConfigFlags::DontIndexOverflow),
+];
+
+impl ConfigFlags {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        QUIRKS
+            .iter()
+            .find(|(quirk, _)| quirk.eq_ignore_ascii_case(name))
+            .map(|(_, flag)| *flag)
+            .ok_or_else(|| {
+                let names: Vec<&str> = QUIRKS.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "Unknown quirk '{}', expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Config {
     flags: u8,
 }
@@ -40,6 +70,112 @@ impl Config {
         let res = self.flags & flag as u8;
         res > 0
     }
+
+    pub fn set_flag(&mut self, flag: ConfigFlags, on: bool) {
+        if on {
+            self.flags |= flag as u8;
+        } else {
+            self.flags &= !(flag as u8);
+        }
+    }
+}
+
+// Parses "name", "name=on" or "name=off"
+pub fn parse_quirk(quirk: &str) -> Result<(ConfigFlags, bool), String> {
+    let (name, value) = quirk.split_once('=').unwrap_or((quirk, "on"));
+    let on = match value.to_lowercase().as_str() {
+        "on" | "true" | "1" => true,
+        "off" | "false" | "0" => false,
+        _ => return Err(format!("Invalid value '{}' for quirk {}", value, name)),
+    };
+    Ok((ConfigFlags::parse(name)?, on))
+}
+
+// Systems a program can be written for, each with its own quirks and speed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Platform {
+    #[default]
+    Chip8,
+    SuperChip,
+    XoChip,
+    // CHIP-8 with the two page 64x64 display of the HIRES interpreter
+    Hires,
+}
+
+impl Platform {
+    pub const NAMES: [&'static str; 4] = ["chip8", "schip", "xochip", "hires"];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "chip8" | "chip-8" => Ok(Platform::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            "hires" | "hires-chip8" => Ok(Platform::Hires),
+            _ => Err(format!(
+                "Unknown platform '{}', expected one of {}",
+                name,
+                Platform::NAMES.join(", ")
+            )),
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        match self {
+            Platform::Chip8 | Platform::XoChip | Platform::Hires => Config::default(),
+            Platform::SuperChip => Config::from(
+                ConfigFlags::Shift | ConfigFlags::JumpWithOffset | ConfigFlags::StoreLoadMem,
+            ),
+        }
+    }
+
+    // Best guess from the instructions a program can reach
+    pub fn guess(program: &[u8]) -> Self {
+        let mut platform = Platform::Chip8;
+        for (_, opcode) in reachable_instructions(program) {
+            match (opcode >> 12, (opcode >> 8) & 0xF, opcode & 0x00FF) {
+                // F000 NNNN, F002, FN01 and FX3A
+                (0xF, 0x0, 0x00 | 0x02) | (0xF, _, 0x01 | 0x3A) => return Platform::XoChip,
+                // 5XY2 and 5XY3
+                (0x5, _, _) if matches!(opcode & 0xF, 0x2 | 0x3) => return Platform::XoChip,
+                // 00CN, 00FB-00FF, FX30, FX75 and FX85
+                (0x0, 0x0, 0xC0..=0xCF | 0xFB..=0xFF) | (0xF, _, 0x30 | 0x75 | 0x85) => {
+                    platform = Platform::SuperChip
+                }
+                _ => {}
+            }
+        }
+        platform
+    }
+
+    // From the .ch8, .sc8 or .xo8 extension
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        match extension.as_str() {
+            "ch8" => Some(Platform::Chip8),
+            "sc8" => Some(Platform::SuperChip),
+            "xo8" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        Platform::NAMES[*self as usize]
+    }
+
+    // Bytes available to a program. XO-CHIP allows 64K on other interpreters,
+    // here every platform gets the same 4K of memory
+    pub fn program_space(&self) -> usize {
+        MEM_SIZE - PROGRAM_START
+    }
+
+    // Instructions run per 60Hz frame
+    pub fn tickrate(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::Hires => CLOCK_SPEED / 60,
+            Platform::SuperChip => 30,
+            Platform::XoChip => 100,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -74,4 +210,41 @@ mod test {
         assert_eq!(c.flag_set(ConfigFlags::JumpWithOffset), true);
         assert_eq!(c.flag_set(ConfigFlags::StoreLoadMem), true);
     }
+
+    #[test]
+    fn test_quirks_and_platforms() {
+        let mut c = Platform::parse("SCHIP").unwrap().config();
+        assert!(c.flag_set(ConfigFlags::Shift));
+
+        for quirk in ["shift=off", "index-overflow"] {
+            let (flag, on) = parse_quirk(quirk).unwrap();
+            c.set_flag(flag, on);
+        }
+        assert!(!c.flag_set(ConfigFlags::Shift));
+        assert!(c.flag_set(ConfigFlags::DontIndexOverflow));
+        assert!(c.flag_set(ConfigFlags::JumpWithOffset));
+
+        assert!(parse_quirk("wrap").is_err());
+        assert!(parse_quirk("shift=maybe").is_err());
+        assert!(Platform::parse("megachip").is_err());
+
+        assert_eq!(Platform::guess(&[0x00, 0xE0, 0x12, 0x00]), Platform::Chip8);
+        assert_eq!(
+            Platform::guess(&[0x00, 0xFF, 0x12, 0x00]),
+            Platform::SuperChip
+        );
+        assert_eq!(Platform::guess(&[0x00, 0xFF, 0xF0, 0x02]), Platform::XoChip);
+        assert_eq!(Platform::guess(&[0xF3, 0x01, 0x12, 0x02]), Platform::XoChip);
+        // Only F002 and F000 are XO-CHIP, not FX02 or FX00 for other X
+        assert_eq!(Platform::guess(&[0xF1, 0x02, 0x12, 0x02]), Platform::Chip8);
+        assert_eq!(Platform::guess(&[0xF2, 0x00, 0x12, 0x02]), Platform::Chip8);
+        assert_eq!(Platform::XoChip.name(), "xochip");
+        assert_eq!(Platform::Hires.name(), "hires");
+
+        assert_eq!(
+            Platform::from_extension(Path::new("games/Blinky.SC8")),
+            Some(Platform::SuperChip)
+        );
+        assert_eq!(Platform::from_extension(Path::new("rom.bin")), None);
+    }
 }
@@ -1,10 +1,12 @@
+#[cfg(feature = "sdl")]
 pub const X_PIXELS: u32 = 64; // Width of screen in pixels
+#[cfg(feature = "sdl")]
 pub const Y_PIXELS: u32 = 32; // Height of screen in pixels
 pub const PIXEL_SIZE: u32 = 16;
 pub const PROGRAM_START: usize = 0x200; // Memeory adress for the first program instruction
+pub const HIRES_START: usize = 0x2C0; // Entry point of HIRES CHIP-8 programs
 pub const FONT_ADDR: usize = 0x50;
 pub const MEM_SIZE: usize = 4096;
-pub const VRAM_SIZE: usize = (X_PIXELS * Y_PIXELS) as usize * 3;
-pub const ON: u8 = 255;
-pub const OFF: u8 = 0;
+pub const STACK_SIZE: usize = 16; // Maximum subroutine nesting depth
 pub const CLOCK_SPEED: usize = 700; // Instructions per second
+pub const DEFAULT_PITCH: u8 = 64; // XO-CHIP pitch that plays audio patterns at 4000 bits per second
@@ -1,6 +1,11 @@
-use rand::Rng;
+use std::collections::VecDeque;
+use std::fmt;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use crate::drivers::input_driver::InputManager;
+use crate::framebuffer::{Framebuffer, Resolution, PLANES};
 
 use crate::{
     config::{Config, ConfigFlags},
@@ -27,6 +32,67 @@ const FONT: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+// Number of executed instructions kept for crash reports
+const HISTORY_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuError {
+    UnknownOpcode { opcode: u16, addr: usize },
+    StackUnderflow { addr: usize },
+    StackOverflow { addr: usize },
+    // `pc` is the address of the instruction that made the access
+    BadMemoryAccess { addr: usize, pc: usize },
+}
+
+impl CpuError {
+    // Address of the instruction that failed
+    pub fn addr(&self) -> usize {
+        match *self {
+            CpuError::UnknownOpcode { addr, .. } => addr,
+            CpuError::StackUnderflow { addr } => addr,
+            CpuError::StackOverflow { addr } => addr,
+            CpuError::BadMemoryAccess { pc, .. } => pc,
+        }
+    }
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { opcode, addr } => {
+                write!(f, "Unknown opcode {:#06x} @ pc = {:#05x}", opcode, addr)
+            }
+            CpuError::StackUnderflow { addr } => {
+                write!(f, "Return with an empty stack @ pc = {:#05x}", addr)
+            }
+            CpuError::StackOverflow { addr } => write!(
+                f,
+                "Subroutine nested deeper than {} levels @ pc = {:#05x}",
+                STACK_SIZE, addr
+            ),
+            CpuError::BadMemoryAccess { addr, pc } => write!(
+                f,
+                "Memory access out of bounds at {:#06x} @ pc = {:#05x}",
+                addr, pc
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    // Instruction fetch, covers both bytes of the opcode
+    Execute,
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemAccess {
+    pub addr: usize,
+    pub kind: AccessKind,
+}
+
 pub struct CPU {
     pub memory: [u8; MEM_SIZE],
     pub pc: usize,
@@ -34,11 +100,23 @@ pub struct CPU {
     pub stack: Vec<u16>,
     pub delay_timer: u8,
     pub sound_timer: u8,
+    // XO-CHIP 1-bit audio pattern loaded by F002, played at the rate set by FX3A
+    pub audio_pattern: Option<[u8; 16]>,
+    pub pitch: u8,
     pub input: InputManager,
     pub reg_v: [u8; 16],
-    pub vram: [u8; 64 * 32 * 3],
+    pub framebuffer: Framebuffer,
+    // Bitmask of the planes drawn to and cleared, selected by FN01
+    pub planes: u8,
+    // Set when the screen changes, frontends reset it once the frame is presented
     pub update_screen: bool,
     pub config: Config,
+    // When set, memory accessed by the last instruction is recorded in `accesses`
+    pub trace_memory: bool,
+    pub accesses: Vec<MemAccess>,
+    // Address and opcode of the most recently executed instructions, oldest first
+    pub history: VecDeque<(usize, u16)>,
+    rng: StdRng,
 }
 
 impl CPU {
@@ -50,11 +128,18 @@ impl CPU {
             stack: Vec::new(),
             delay_timer: 0,
             sound_timer: 0,
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
             input: InputManager::new(),
             reg_v: [0; 16],
-            vram: [0; 64 * 32 * 3],
+            framebuffer: Framebuffer::new(),
+            planes: 1,
             update_screen: true,
             config,
+            trace_memory: false,
+            accesses: Vec::new(),
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            rng: StdRng::from_entropy(),
         };
 
         cpu.memory[FONT_ADDR..(FONT.len() + FONT_ADDR)].copy_from_slice(&FONT[..]);
@@ -86,97 +171,214 @@ impl CPU {
         println!();
     }
 
+    // Anything past the end of memory is dropped, Program::check reports
+    // ROMs that don't fit before they get here
     pub fn load_program(&mut self, program: Vec<u8>) {
-        self.memory[PROGRAM_START..(program.len() + PROGRAM_START)].copy_from_slice(&program[..])
+        let len = program.len().min(MEM_SIZE - PROGRAM_START);
+        self.memory[PROGRAM_START..(len + PROGRAM_START)].copy_from_slice(&program[..len]);
+    }
+
+    // HIRES CHIP-8 programs start by jumping into a patched interpreter at
+    // 0x260, which switches to 64x64 and continues at 0x2C0. Only done for
+    // the hires platform, other programs can start with the same jump
+    pub fn start_hires(&mut self) {
+        self.set_resolution(Resolution::Tall);
+        self.pc = HIRES_START;
     }
 
     // Main loop
 
-    fn fetch(&mut self) -> (u8, u8, u8, u8) {
+    fn fetch(&mut self) -> Result<(u8, u8, u8, u8), CpuError> {
+        if self.pc + 1 >= MEM_SIZE {
+            return Err(CpuError::BadMemoryAccess {
+                addr: self.pc,
+                pc: self.pc,
+            });
+        }
+
         let first_byte = self.memory[self.pc];
         let second_byte = self.memory[self.pc + 1];
         let instruction: u16 = (first_byte as u16) << 8 | second_byte as u16;
         self.pc += 2;
 
-        (
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back((self.pc - 2, instruction));
+
+        Ok((
             ((instruction & 0xF000) >> 12) as u8,
             ((instruction & 0x0F00) >> 8) as u8,
             ((instruction & 0x00F0) >> 4) as u8,
             (instruction & 0x000F) as u8,
-        )
+        ))
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    // Decrements cpu timers, should be called at 60hz
+    pub fn tick_timers(&mut self) {
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+    }
+
+    // Whether the screen changed since the last call. Frontends call it once a
+    // frame and present when it did, however many times the program drew
+    pub fn take_screen_update(&mut self) -> bool {
+        std::mem::take(&mut self.update_screen)
+    }
+
+    // Runs until an instruction fails
+    #[cfg(feature = "sdl")]
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<(), CpuError>
     where
         F: FnMut(&mut CPU),
     {
         loop {
             callback(self);
-            // Fetch
-            let instruction = self.fetch();
-
-            // Decode
-            let x = instruction.1 as usize;
-            let y = instruction.2 as usize;
-            let n = instruction.3 as usize;
-            let nn = y << 4 | n;
-            let nnn = x << 8 | y << 4 | n;
-            // Execute
-            match instruction {
-                (0x0, 0x0, 0xe, 0x0) => self.clear_screen(),
-                (0x0, 0x0, 0xe, 0xe) => self.sub_return(),
-                (0xf, _, 0x6, 0x5) => self.load_mem(x),
-                (0xf, _, 0x5, 0x5) => self.store_mem(x),
-                (0xf, _, 0x3, 0x3) => self.bcd_conversion(x),
-                (0xf, _, 0x2, 0x9) => self.font_character(x),
-                (0xf, _, 0x1, 0xe) => self.add_to_index(x),
-                (0xf, _, 0x1, 0x8) => self.set_sound_timer(x),
-                (0xf, _, 0x1, 0x5) => self.set_delay_timer(x),
-                (0xf, _, 0x0, 0xa) => self.get_key(x),
-                (0xf, _, 0x0, 0x7) => self.get_delay_timer(x),
-                (0xe, _, 0xa, 0x1) => self.skip_if_up(x),
-                (0xe, _, 0x9, 0xe) => self.skip_if_down(x),
-                (0x9, _, _, 0x0) => self.vy_skip_not_eq(x, y),
-                (0x8, _, _, 0x0) => self.set_vx(x, y),
-                (0x8, _, _, 0x1) => self.binary_or(x, y),
-                (0x8, _, _, 0x2) => self.binary_and(x, y),
-                (0x8, _, _, 0x3) => self.logical_xor(x, y),
-                (0x8, _, _, 0x4) => self.add_vx_vy(x, y),
-                (0x8, _, _, 0x5) => self.vx_sub_vy(x, y),
-                (0x8, _, _, 0x6) => self.shift_right(x, y),
-                (0x8, _, _, 0x7) => self.vy_sub_vx(x, y),
-                (0x8, _, _, 0xe) => self.shift_left(x, y),
-                (0x5, _, _, 0x0) => self.vy_skip_eq(x, y),
-                (0xd, _, _, _) => self.display(x, y, n),
-                (0xc, _, _, _) => self.random(x, nn),
-                (0xb, _, _, _) => self.jump_offset(x, nnn),
-                (0xa, _, _, _) => self.set_index(nnn),
-                (0x7, _, _, _) => self.add_reg_v(x, nn),
-                (0x6, _, _, _) => self.set_reg_v(x, nn),
-                (0x4, _, _, _) => self.vx_skip_not_eq(x, nn),
-                (0x3, _, _, _) => self.vx_skip_eq(x, nn),
-                (0x2, _, _, _) => self.subroutine(nnn),
-                (0x1, _, _, _) => self.jump(nnn),
-                _ => todo!(
-                    "Unimplemented opcode: {:#x}, {:#x}, {:#x}, {:#x} @ pc = {:#x}",
-                    instruction.0,
-                    instruction.1,
-                    instruction.2,
-                    instruction.3,
-                    self.pc - 2
-                ),
+            self.step()?;
+        }
+    }
+
+    fn log_access(&mut self, addr: usize, kind: AccessKind) {
+        if self.trace_memory {
+            self.accesses.push(MemAccess { addr, kind });
+        }
+    }
+
+    fn read_mem(&mut self, addr: usize) -> Result<u8, CpuError> {
+        if addr >= MEM_SIZE {
+            return Err(self.bad_access(addr));
+        }
+        self.log_access(addr, AccessKind::Read);
+        Ok(self.memory[addr])
+    }
+
+    fn write_mem(&mut self, addr: usize, value: u8) -> Result<(), CpuError> {
+        if addr >= MEM_SIZE {
+            return Err(self.bad_access(addr));
+        }
+        self.log_access(addr, AccessKind::Write);
+        self.memory[addr] = value;
+        Ok(())
+    }
+
+    // Only valid while executing, after pc has moved past the instruction
+    fn bad_access(&self, addr: usize) -> CpuError {
+        CpuError::BadMemoryAccess {
+            addr,
+            pc: self.pc - 2,
+        }
+    }
+
+    // Fetches, decodes and executes a single instruction
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        let addr = self.pc;
+        let result = self.execute();
+
+        // Leave pc on the failing instruction so it can be inspected
+        if result.is_err() {
+            self.pc = addr;
+        }
+        result
+    }
+
+    fn execute(&mut self) -> Result<(), CpuError> {
+        self.accesses.clear();
+        self.log_access(self.pc, AccessKind::Execute);
+
+        // Fetch
+        let instruction = self.fetch()?;
+
+        // Decode
+        let x = instruction.1 as usize;
+        let y = instruction.2 as usize;
+        let n = instruction.3 as usize;
+        let nn = y << 4 | n;
+        let nnn = x << 8 | y << 4 | n;
+        // Execute
+        match instruction {
+            (0x0, 0x0, 0xe, 0x0) => self.clear_screen(),
+            (0x0, 0x0, 0xe, 0xe) => self.sub_return()?,
+            (0x0, 0x0, 0xf, 0xe) => self.set_resolution(Resolution::Low),
+            (0x0, 0x0, 0xf, 0xf) => self.set_resolution(Resolution::High),
+            (0xf, _, 0x0, 0x1) => self.select_planes(x),
+            (0xf, 0x0, 0x0, 0x2) => self.load_audio_pattern()?,
+            (0xf, _, 0x3, 0xa) => self.set_pitch(x),
+            (0xf, _, 0x6, 0x5) => self.load_mem(x)?,
+            (0xf, _, 0x5, 0x5) => self.store_mem(x)?,
+            (0xf, _, 0x3, 0x3) => self.bcd_conversion(x)?,
+            (0xf, _, 0x2, 0x9) => self.font_character(x),
+            (0xf, _, 0x1, 0xe) => self.add_to_index(x),
+            (0xf, _, 0x1, 0x8) => self.set_sound_timer(x),
+            (0xf, _, 0x1, 0x5) => self.set_delay_timer(x),
+            (0xf, _, 0x0, 0xa) => self.get_key(x),
+            (0xf, _, 0x0, 0x7) => self.get_delay_timer(x),
+            (0xe, _, 0xa, 0x1) => self.skip_if_up(x),
+            (0xe, _, 0x9, 0xe) => self.skip_if_down(x),
+            (0x9, _, _, 0x0) => self.vy_skip_not_eq(x, y),
+            (0x8, _, _, 0x0) => self.set_vx(x, y),
+            (0x8, _, _, 0x1) => self.binary_or(x, y),
+            (0x8, _, _, 0x2) => self.binary_and(x, y),
+            (0x8, _, _, 0x3) => self.logical_xor(x, y),
+            (0x8, _, _, 0x4) => self.add_vx_vy(x, y),
+            (0x8, _, _, 0x5) => self.vx_sub_vy(x, y),
+            (0x8, _, _, 0x6) => self.shift_right(x, y),
+            (0x8, _, _, 0x7) => self.vy_sub_vx(x, y),
+            (0x8, _, _, 0xe) => self.shift_left(x, y),
+            (0x5, _, _, 0x0) => self.vy_skip_eq(x, y),
+            (0xd, _, _, _) => self.display(x, y, n)?,
+            (0xc, _, _, _) => self.random(x, nn),
+            (0xb, _, _, _) => self.jump_offset(x, nnn),
+            (0xa, _, _, _) => self.set_index(nnn),
+            (0x7, _, _, _) => self.add_reg_v(x, nn),
+            (0x6, _, _, _) => self.set_reg_v(x, nn),
+            (0x4, _, _, _) => self.vx_skip_not_eq(x, nn),
+            (0x3, _, _, _) => self.vx_skip_eq(x, nn),
+            (0x2, _, _, _) => self.subroutine(nnn)?,
+            (0x1, _, _, _) => self.jump(nnn),
+            _ => {
+                return Err(CpuError::UnknownOpcode {
+                    opcode: (nnn | (instruction.0 as usize) << 12) as u16,
+                    addr: self.pc - 2,
+                })
             }
         }
+
+        Ok(())
     }
 
     // Opcodes
     fn clear_screen(&mut self) {
-        for i in 0..VRAM_SIZE {
-            self.vram[i] = 0;
-        }
+        self.framebuffer.clear(self.planes);
+        self.update_screen = true;
+    }
+
+    fn set_resolution(&mut self, resolution: Resolution) {
+        self.framebuffer.set_resolution(resolution);
         self.update_screen = true;
     }
 
+    fn select_planes(&mut self, x: usize) {
+        self.planes = x as u8;
+    }
+
+    fn load_audio_pattern(&mut self) -> Result<(), CpuError> {
+        let mut pattern = [0; 16];
+        for (offset, byte) in pattern.iter_mut().enumerate() {
+            *byte = self.read_mem(self.reg_i as usize + offset)?;
+        }
+        self.audio_pattern = Some(pattern);
+        Ok(())
+    }
+
+    fn set_pitch(&mut self, x: usize) {
+        self.pitch = self.reg_v[x];
+    }
+
     fn set_index(&mut self, nnn: usize) {
         self.reg_i = nnn as u16;
     }
@@ -195,13 +397,23 @@ impl CPU {
         self.pc = nnn;
     }
 
-    fn subroutine(&mut self, nnn: usize) {
+    fn subroutine(&mut self, nnn: usize) -> Result<(), CpuError> {
+        if self.stack.len() == STACK_SIZE {
+            return Err(CpuError::StackOverflow { addr: self.pc - 2 });
+        }
         self.stack.push(self.pc as u16);
         self.pc = nnn;
+        Ok(())
     }
 
-    fn sub_return(&mut self) {
-        self.pc = self.stack.pop().unwrap() as usize;
+    fn sub_return(&mut self) -> Result<(), CpuError> {
+        match self.stack.pop() {
+            Some(addr) => {
+                self.pc = addr as usize;
+                Ok(())
+            }
+            None => Err(CpuError::StackUnderflow { addr: self.pc - 2 }),
+        }
     }
 
     fn vx_skip_eq(&mut self, x: usize, nn: usize) {
@@ -334,13 +546,18 @@ impl CPU {
         };
     }
 
+    // Makes CXNN repeatable between runs
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
     fn random(&mut self, x: usize, nn: usize) {
-        let mut rng = rand::thread_rng();
-        self.reg_v[x] = rng.gen::<u8>() & (nn as u8);
+        self.reg_v[x] = self.rng.gen::<u8>() & (nn as u8);
     }
 
+    // Only the low nibble of VX names a key, like the original interpreter
     fn skip_if_down(&mut self, x: usize) {
-        let vx: u8 = self.reg_v[x];
+        let vx: u8 = self.reg_v[x] & 0xF;
 
         if self.input.check_key_pressed(vx) {
             self.pc += 2
@@ -348,7 +565,7 @@ impl CPU {
     }
 
     fn skip_if_up(&mut self, x: usize) {
-        let vx: u8 = self.reg_v[x];
+        let vx: u8 = self.reg_v[x] & 0xF;
 
         if self.input.check_key_released(vx) {
             self.pc += 2
@@ -398,62 +615,64 @@ impl CPU {
         self.reg_i = addr as u16;
     }
 
-    fn bcd_conversion(&mut self, x: usize) {
+    fn bcd_conversion(&mut self, x: usize) -> Result<(), CpuError> {
         let vx = self.reg_v[x] as f32;
 
         let hundreds = (vx / 100.0).floor() as u8;
         let tens = ((vx / 10.0) % 10.0).floor() as u8;
         let ones = (vx % 10.0) as u8;
 
-        self.memory[self.reg_i as usize] = hundreds;
-        self.memory[(self.reg_i + 1) as usize] = tens;
-        self.memory[(self.reg_i + 2) as usize] = ones;
+        let addr = self.reg_i as usize;
+        self.write_mem(addr, hundreds)?;
+        self.write_mem(addr + 1, tens)?;
+        self.write_mem(addr + 2, ones)
     }
 
-    fn store_mem(&mut self, x: usize) {
+    // I can be anywhere up to 0xFFFF, addresses past memory are bad accesses
+    fn store_mem(&mut self, x: usize) -> Result<(), CpuError> {
         for offset in 0..=x {
-            if !self.config.flag_set(ConfigFlags::StoreLoadMem) {
-                let value = self.reg_v[offset];
-                self.memory[self.reg_i as usize] = value;
-                self.reg_i += 1;
-            } else {
-                let value = self.reg_v[offset];
-                let addr = self.reg_i + offset as u16;
-                self.memory[addr as usize] = value;
-            }
+            let value = self.reg_v[offset];
+            self.write_mem(self.reg_i as usize + offset, value)?;
+        }
+        // Every address was in memory, so this can't overflow
+        if !self.config.flag_set(ConfigFlags::StoreLoadMem) {
+            self.reg_i += x as u16 + 1;
         }
+        Ok(())
     }
 
-    fn load_mem(&mut self, x: usize) {
+    fn load_mem(&mut self, x: usize) -> Result<(), CpuError> {
         for offset in 0..=x {
-            if !self.config.flag_set(ConfigFlags::StoreLoadMem) {
-                let value = self.memory[self.reg_i as usize];
-                self.reg_v[offset] = value;
-                self.reg_i += 1;
-            } else {
-                let addr = self.reg_i + offset as u16;
-                let value = self.memory[addr as usize];
-                self.reg_v[offset] = value;
-            }
+            self.reg_v[offset] = self.read_mem(self.reg_i as usize + offset)?;
+        }
+        if !self.config.flag_set(ConfigFlags::StoreLoadMem) {
+            self.reg_i += x as u16 + 1;
         }
+        Ok(())
     }
 
-    fn display(&mut self, x: usize, y: usize, n: usize) {
-        self.reg_v[0x0f] = 0;
-        for byte in 0..n {
-            let y = (self.reg_v[y] as usize + byte) % Y_PIXELS as usize;
-            for bit in 0..8 {
-                let x = (self.reg_v[x] as usize + bit) % X_PIXELS as usize;
-                let color = (self.memory[(self.reg_i + byte as u16) as usize] >> (7 - bit)) & 1;
-                let vram_addr = (y * X_PIXELS as usize * 3) + (x * 3);
-                let new_color = (self.vram[vram_addr] / ON) ^ color;
-                self.reg_v[0x0f] |= color & (self.vram[vram_addr] / ON);
-                self.vram[vram_addr] = new_color * ON;
-                self.vram[vram_addr + 1] = new_color * ON;
-                self.vram[vram_addr + 2] = new_color * ON;
+    // Each selected plane gets its own n bytes of sprite data, one after another
+    fn display(&mut self, x: usize, y: usize, n: usize) -> Result<(), CpuError> {
+        let x = self.reg_v[x] as usize;
+        let y = self.reg_v[y] as usize;
+        let mut addr = self.reg_i as usize;
+        let mut collision = false;
+
+        for plane in 0..PLANES {
+            if self.planes & (1 << plane) == 0 {
+                continue;
+            }
+            let mut rows = Vec::with_capacity(n);
+            for _ in 0..n {
+                rows.push(self.read_mem(addr)?);
+                addr += 1;
             }
+            collision |= self.framebuffer.draw_sprite(plane, x, y, &rows);
         }
+
+        self.reg_v[0x0f] = collision as u8;
         self.update_screen = true;
+        Ok(())
     }
 }
 
@@ -461,6 +680,115 @@ impl CPU {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_seed() {
+        let run = || {
+            let mut cpu = CPU::new(Config::default());
+            cpu.seed(42);
+            // RND V0, 0xff
+            cpu.memory[PROGRAM_START..PROGRAM_START + 4].copy_from_slice(&[0xC0, 0xFF, 0xC1, 0xFF]);
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            (cpu.reg_v[0], cpu.reg_v[1])
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn test_audio_pattern() {
+        let mut cpu = CPU::new(Config::default());
+        assert_eq!(cpu.audio_pattern, None);
+        assert_eq!(cpu.pitch, DEFAULT_PITCH);
+
+        // LD I, 0x300; F002; LD V1, 0x70; FX3A
+        cpu.memory[PROGRAM_START..PROGRAM_START + 8]
+            .copy_from_slice(&[0xA3, 0x00, 0xF0, 0x02, 0x61, 0x70, 0xF1, 0x3A]);
+        for i in 0..16 {
+            cpu.memory[0x300 + i] = i as u8;
+        }
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+
+        let pattern = cpu.audio_pattern.unwrap();
+        assert_eq!(pattern[0], 0);
+        assert_eq!(pattern[15], 15);
+        assert_eq!(cpu.pitch, 0x70);
+    }
+
+    #[test]
+    fn test_store_load_past_memory() {
+        for quirk in [false, true] {
+            let mut config = Config::default();
+            config.set_flag(ConfigFlags::StoreLoadMem, quirk);
+            let mut cpu = CPU::new(config);
+            // LD [I], V3 then LD V3, [I]
+            cpu.load_program(vec![0xF3, 0x55, 0xF3, 0x65]);
+
+            cpu.reg_i = 0xFFFE;
+            assert_eq!(
+                cpu.step(),
+                Err(CpuError::BadMemoryAccess {
+                    addr: 0xFFFE,
+                    pc: PROGRAM_START
+                })
+            );
+            cpu.pc = PROGRAM_START + 2;
+            cpu.reg_i = 0x0FFE;
+            assert!(matches!(
+                cpu.step(),
+                Err(CpuError::BadMemoryAccess { addr: 0x1000, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_skip_key_masked() {
+        let mut cpu = CPU::new(Config::default());
+        // SKP V0, (skipped), SKNP V0
+        cpu.load_program(vec![0xE0, 0x9E, 0x00, 0x00, 0xE0, 0xA1]);
+        cpu.reg_v[0] = 0xF5;
+        cpu.input.set_keys(1 << 5);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, PROGRAM_START + 4);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, PROGRAM_START + 6);
+    }
+
+    #[test]
+    fn test_screen_updates() {
+        let mut cpu = CPU::new(Config::default());
+        // The first frame is always presented
+        assert!(cpu.take_screen_update());
+        assert!(!cpu.take_screen_update());
+
+        // LD I, 0x50; DRW V0, V0, 5; DRW V0, V0, 5
+        cpu.load_program(vec![0xA0, 0x50, 0xD0, 0x05, 0xD0, 0x05]);
+        cpu.step().unwrap();
+        assert!(!cpu.update_screen);
+        cpu.step().unwrap();
+        assert!(cpu.update_screen);
+        cpu.step().unwrap();
+
+        // Two draws in a frame, one present
+        let presents = (0..2).filter(|_| cpu.take_screen_update()).count();
+        assert_eq!(presents, 1);
+        assert!(!cpu.update_screen);
+    }
+
+    #[test]
+    fn test_start_hires() {
+        let mut cpu = CPU::new(Config::default());
+        cpu.load_program(vec![0x12, 0x60]);
+        assert_eq!(cpu.pc, PROGRAM_START);
+        assert_eq!(cpu.framebuffer.height(), 32);
+
+        cpu.start_hires();
+        assert_eq!(cpu.pc, HIRES_START);
+        assert_eq!(cpu.framebuffer.height(), 64);
+    }
+
     mod shifts {
         use super::*;
 
@@ -532,5 +860,4 @@ mod tests {
             assert_eq!(cpu.reg_v[0xF], 1);
         }
     }
-
 }
@@ -0,0 +1,247 @@
+/*
+ROM metadata database.
+
+ROMs are identified by the SHA-1 hash of their bytes and looked up in a file in
+the format of the community CHIP-8 database's programs.json
+(https://github.com/chip-8/chip-8-database). Only the ROMs shipped with the
+emulator are bundled, dropping the full programs.json into the config
+directory makes every ROM it knows about recognised:
+
+    ~/.config/chip8/programs.json
+*/
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Platform;
+use crate::settings::{self, Settings};
+
+const BUNDLED: &str = include_str!("../roms/programs.json");
+
+#[derive(Deserialize)]
+struct Entry {
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    roms: BTreeMap<String, Rom>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Rom {
+    platforms: Vec<String>,
+    quirky_platforms: BTreeMap<String, BTreeMap<String, bool>>,
+    tickrate: Option<usize>,
+    colors: Colours,
+    keys: BTreeMap<String, u8>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Colours {
+    pixels: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub title: String,
+    pub authors: Vec<String>,
+    // First of the ROM's platforms that can be emulated
+    pub platform: Option<Platform>,
+    // Quirks that differ from the platform's, by the names in config::QUIRKS
+    pub quirks: BTreeMap<String, bool>,
+    pub tickrate: Option<usize>,
+    // Background first, then the foreground and the colours of the other planes
+    pub colours: Vec<String>,
+    // Keypad key for each action, like "up" or "a"
+    pub keys: BTreeMap<String, u8>,
+}
+
+impl Metadata {
+    // Settings to run the ROM with, below the per-ROM files and the command line
+    pub fn settings(&self) -> Settings {
+        let mut settings = Settings {
+            platform: self.platform.map(|platform| platform.name().to_string()),
+            quirks: self.quirks.clone(),
+            ..Settings::default()
+        };
+        settings.timing.tickrate = self.tickrate.filter(|&tickrate| tickrate > 0);
+
+        let colours: Vec<String> = self
+            .colours
+            .iter()
+            .map(|colour| colour.trim_start_matches('#').to_string())
+            .collect();
+        match colours.as_slice() {
+            [bg, fg] => {
+                settings.display.bg = Some(bg.clone());
+                settings.display.fg = Some(fg.clone());
+            }
+            [_, _, _, _] => settings.display.palette = Some(colours.join(",")),
+            _ => {}
+        }
+
+        // Drop anything the emulator wouldn't accept rather than refusing to run
+        if settings.validate().is_err() {
+            settings.display = Default::default();
+        }
+        settings
+    }
+}
+
+pub struct Database {
+    entries: Vec<Entry>,
+}
+
+impl Database {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let entries = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        Ok(Database { entries })
+    }
+
+    // programs.json from the config directory if there is one, the bundled
+    // subset otherwise
+    pub fn load() -> Result<Self, String> {
+        Database::load_from(settings::config_dir().as_deref())
+    }
+
+    pub fn load_from(config_dir: Option<&Path>) -> Result<Self, String> {
+        let path = config_dir.map(|dir| dir.join("programs.json"));
+        match path.filter(|path| path.exists()) {
+            Some(path) => fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| Database::parse(&text))
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            None => Database::bundled(),
+        }
+    }
+
+    pub fn bundled() -> Result<Self, String> {
+        Database::parse(BUNDLED)
+    }
+
+    pub fn lookup(&self, hash: &str) -> Option<Metadata> {
+        self.entries.iter().find_map(|entry| {
+            let rom = entry.roms.get(hash)?;
+            let (id, platform) = rom
+                .platforms
+                .iter()
+                .find_map(|id| platform(id).map(|platform| (id, platform)))
+                .unzip();
+
+            let mut quirks = BTreeMap::new();
+            let quirky = id.and_then(|id| rom.quirky_platforms.get(id));
+            for (name, on) in quirky.into_iter().flatten() {
+                if let Some(quirk) = quirk(name) {
+                    quirks.insert(quirk.to_string(), *on);
+                }
+            }
+
+            Some(Metadata {
+                title: entry.title.clone(),
+                authors: entry.authors.clone(),
+                platform,
+                quirks,
+                tickrate: rom.tickrate,
+                colours: rom.colors.pixels.clone(),
+                keys: rom.keys.clone(),
+            })
+        })
+    }
+}
+
+// Platform, quirks, speed and colours the database knows the ROM needs. Unknown
+// ROMs only get a platform from their file extension
+pub fn profile(metadata: Option<&Metadata>, rom_path: Option<&Path>) -> Settings {
+    let mut profile = metadata.map(Metadata::settings).unwrap_or_default();
+    if profile.platform.is_none() {
+        profile.platform = rom_path
+            .and_then(Platform::from_extension)
+            .map(|platform| platform.name().to_string());
+    }
+    profile
+}
+
+pub fn sha1(bytes: &[u8]) -> String {
+    sha1_smol::Sha1::from(bytes).digest().to_string()
+}
+
+// Platform ids used by the database. CHIP-8X and MEGA-CHIP aren't emulated
+fn platform(id: &str) -> Option<Platform> {
+    match id {
+        "originalChip8" | "hybridVIP" | "modernChip8" | "chip48" => Some(Platform::Chip8),
+        "superchip1" | "superchip" => Some(Platform::SuperChip),
+        "xochip" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+// Database quirk names, the others have no matching ConfigFlags
+fn quirk(name: &str) -> Option<&'static str> {
+    match name {
+        "shift" => Some("shift"),
+        "jump" => Some("jump"),
+        "memoryLeaveIUnchanged" => Some("load-store"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled() {
+        let database = Database::bundled().unwrap();
+        let corax = include_bytes!("../roms/tests/3-corax+.ch8");
+
+        let metadata = database.lookup(&sha1(corax)).unwrap();
+        assert_eq!(metadata.title, "Corax+ opcode test");
+        assert_eq!(metadata.platform, Some(Platform::Chip8));
+        assert!(database.lookup(&sha1(&[0x12, 0x00])).is_none());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let database = Database::parse(
+            r##"[{
+                "title": "Demo",
+                "authors": ["Someone"],
+                "description": "Not read",
+                "roms": {
+                    "abc": {
+                        "file": "demo.ch8",
+                        "platforms": ["megachip8", "superchip", "xochip"],
+                        "quirkyPlatforms": {
+                            "superchip": { "shift": false, "vblank": true },
+                            "xochip": { "jump": true }
+                        },
+                        "tickrate": 20,
+                        "colors": { "pixels": ["#000000", "#ff8800"], "buzzer": "#ffffff" },
+                        "keys": { "up": 5, "a": 6 }
+                    }
+                }
+            }]"##,
+        )
+        .unwrap();
+
+        let metadata = database.lookup("abc").unwrap();
+        assert_eq!(metadata.platform, Some(Platform::SuperChip));
+        assert_eq!(metadata.quirks.len(), 1);
+        assert!(!metadata.quirks["shift"]);
+        assert_eq!(metadata.keys["up"], 5);
+
+        let settings = metadata.settings();
+        assert_eq!(settings.platform.as_deref(), Some("schip"));
+        assert_eq!(settings.timing.tickrate, Some(20));
+        assert_eq!(settings.display.fg.as_deref(), Some("ff8800"));
+        settings.validate().unwrap();
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(sha1(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+}
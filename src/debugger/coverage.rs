@@ -0,0 +1,202 @@
+/*
+Code/data coverage map of memory.
+
+Tracks whether each byte was executed as part of an instruction, read as
+sprite or data through I, written, or never touched. The map is exported as
+text, as a PNG with one pixel per byte and as a coverage aware disassembly,
+which makes dead code and unexplored paths in a game easy to spot.
+*/
+
+use std::fs;
+use std::io;
+
+use super::disassembler;
+use crate::constants::*;
+use crate::cpu::{AccessKind, CPU};
+use crate::image;
+
+// First byte of an executed instruction
+pub const INSTRUCTION: u8 = 0b0001;
+pub const EXECUTED: u8 = 0b0010;
+pub const READ: u8 = 0b0100;
+pub const WRITTEN: u8 = 0b1000;
+
+const ROW_SIZE: usize = 64;
+const IMAGE_SCALE: usize = 8;
+
+pub struct Coverage {
+    flags: Vec<u8>,
+    program_end: usize,
+}
+
+impl Coverage {
+    pub fn new(program_len: usize) -> Self {
+        Coverage {
+            flags: vec![0; MEM_SIZE],
+            program_end: (PROGRAM_START + program_len).min(MEM_SIZE),
+        }
+    }
+
+    // Records the memory accessed by the last executed instruction
+    pub fn record(&mut self, cpu: &CPU) {
+        for access in &cpu.accesses {
+            let addr = access.addr % MEM_SIZE;
+            match access.kind {
+                AccessKind::Execute => {
+                    self.flags[addr] |= INSTRUCTION | EXECUTED;
+                    self.flags[(addr + 1) % MEM_SIZE] |= EXECUTED;
+                }
+                AccessKind::Read => self.flags[addr] |= READ,
+                AccessKind::Write => self.flags[addr] |= WRITTEN,
+            }
+        }
+    }
+
+    pub fn flags(&self, addr: usize) -> u8 {
+        self.flags.get(addr).copied().unwrap_or(0)
+    }
+
+    fn symbol(&self, addr: usize) -> char {
+        let flags = self.flags[addr];
+        let data = flags & (READ | WRITTEN);
+
+        if flags & EXECUTED != 0 && data != 0 {
+            '!'
+        } else if flags & EXECUTED != 0 {
+            'C'
+        } else if data == READ | WRITTEN {
+            'B'
+        } else if data == READ {
+            'R'
+        } else if data == WRITTEN {
+            'W'
+        } else {
+            '.'
+        }
+    }
+
+    fn colour(&self, addr: usize) -> [u8; 3] {
+        match self.symbol(addr) {
+            '!' => [0xdc, 0xc8, 0x3c],
+            'C' => [0x3c, 0xc8, 0x3c],
+            'B' => [0xa0, 0x50, 0xdc],
+            'R' => [0x3c, 0x78, 0xdc],
+            'W' => [0xdc, 0x3c, 0x3c],
+            _ if (PROGRAM_START..self.program_end).contains(&addr) => [0x50, 0x50, 0x50],
+            _ => [0x00, 0x00, 0x00],
+        }
+    }
+
+    // Ranges of program bytes that were never touched
+    fn untouched_ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        let mut start = None;
+
+        for addr in PROGRAM_START..=self.program_end {
+            let untouched = addr < self.program_end && self.flags[addr] == 0;
+            match (untouched, start) {
+                (true, None) => start = Some(addr),
+                (false, Some(s)) => {
+                    ranges.push((s, addr));
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+
+        ranges
+    }
+
+    pub fn text_map(&self) -> String {
+        let program = &self.flags[PROGRAM_START..self.program_end];
+        let count = |f: fn(&u8) -> bool| program.iter().filter(|b| f(b)).count();
+
+        let mut out = String::from("CHIP-8 coverage map\n\n");
+        out.push_str("Legend: C code, R read, W written, B read and written, ! code used as data, . untouched\n\n");
+
+        out.push_str(&format!("Program bytes:  {}\n", program.len()));
+        out.push_str(&format!(
+            "  executed:     {}\n",
+            count(|f| f & EXECUTED != 0)
+        ));
+        out.push_str(&format!("  read:         {}\n", count(|f| f & READ != 0)));
+        out.push_str(&format!(
+            "  written:      {}\n",
+            count(|f| f & WRITTEN != 0)
+        ));
+        out.push_str(&format!("  untouched:    {}\n", count(|f| *f == 0)));
+
+        let ranges = self.untouched_ranges();
+        if !ranges.is_empty() {
+            out.push_str("\nUntouched program ranges\n");
+            for (start, end) in ranges {
+                out.push_str(&format!(
+                    "  {:#05x}-{:#05x} ({} bytes)\n",
+                    start,
+                    end - 1,
+                    end - start
+                ));
+            }
+        }
+
+        out.push('\n');
+        for row in (0..MEM_SIZE).step_by(ROW_SIZE) {
+            let symbols: String = (row..row + ROW_SIZE)
+                .map(|addr| self.symbol(addr))
+                .collect();
+            out.push_str(&format!("{:#05x}  {}\n", row, symbols));
+        }
+
+        out
+    }
+
+    // One scaled up pixel per byte, 64 bytes per row
+    pub fn write_image(&self, path: &str) -> io::Result<()> {
+        let rows = MEM_SIZE / ROW_SIZE;
+        let pixels: Vec<u8> = (0..MEM_SIZE).flat_map(|addr| self.colour(addr)).collect();
+
+        let scaled = image::scale(&pixels, ROW_SIZE, rows, IMAGE_SCALE);
+        image::write_png(path, ROW_SIZE * IMAGE_SCALE, rows * IMAGE_SCALE, &scaled)
+    }
+
+    // Writes the text map to the path, with the image and disassembly next to it
+    pub fn write(&self, path: &str, memory: &[u8]) -> io::Result<()> {
+        fs::write(path, self.text_map())?;
+        self.write_image(&format!("{}.png", path))?;
+
+        let listing =
+            disassembler::disassemble(memory, PROGRAM_START, self.program_end, Some(self));
+        fs::write(format!("{}.asm", path), listing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_record() {
+        let mut cpu = CPU::new(Config::default());
+        cpu.trace_memory = true;
+        // I = 0x20a, draw V0 V0 1, store V0, (never executed), sprite
+        cpu.load_program(vec![
+            0xA2, 0x0A, 0xD0, 0x01, 0xF0, 0x55, 0x00, 0xE0, 0x00, 0x00, 0xFF,
+        ]);
+
+        let mut coverage = Coverage::new(11);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+            coverage.record(&cpu);
+        }
+
+        assert_eq!(coverage.flags(0x200), INSTRUCTION | EXECUTED);
+        assert_eq!(coverage.flags(0x201), EXECUTED);
+        assert_eq!(coverage.flags(0x206), 0);
+        assert_eq!(coverage.flags(0x20a), READ | WRITTEN);
+        assert_eq!(coverage.untouched_ranges(), vec![(0x206, 0x20a)]);
+
+        let map = coverage.text_map();
+        assert!(map.contains("0x200  CCCCCC....B."));
+    }
+}
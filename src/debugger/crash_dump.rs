@@ -0,0 +1,137 @@
+/*
+Post-mortem crash reports.
+
+When an instruction fails the emulator writes crash-<time>.txt with the error,
+registers, timers, stack, the last executed instructions and a disassembly
+around the failing instruction, plus crash-<time>.png with the screen.
+*/
+
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::disassembler::{disassemble, disassemble_instruction};
+use crate::constants::*;
+use crate::cpu::{CpuError, CPU};
+use crate::drivers::video_driver;
+use crate::image;
+use crate::palette::Palette;
+
+// Instructions shown either side of the failing one
+const DISASSEMBLY_WINDOW: usize = 8;
+const SCREENSHOT_SCALE: usize = 8;
+
+pub fn report(cpu: &CPU, error: &CpuError) -> String {
+    let mut out = String::from("CHIP-8 crash report\n\n");
+    out.push_str(&format!("Error: {}\n", error));
+
+    out.push_str("\nRegisters\n");
+    for (i, values) in cpu.reg_v.chunks(4).enumerate() {
+        let registers: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(j, v)| format!("V{:X} {:#04x}", i * 4 + j, v))
+            .collect();
+        out.push_str(&format!("  {}\n", registers.join("  ")));
+    }
+    out.push_str(&format!("  I  {:#05x}  PC {:#05x}\n", cpu.reg_i, cpu.pc));
+
+    out.push_str("\nTimers\n");
+    out.push_str(&format!(
+        "  DT {}  ST {}\n",
+        cpu.delay_timer, cpu.sound_timer
+    ));
+
+    out.push_str("\nStack (most recent first)\n");
+    if cpu.stack.is_empty() {
+        out.push_str("  empty\n");
+    }
+    for (i, addr) in cpu.stack.iter().enumerate().rev() {
+        out.push_str(&format!("  [{}] {:#05x}\n", i, addr));
+    }
+
+    out.push_str("\nHistory (oldest first)\n");
+    for (addr, opcode) in &cpu.history {
+        out.push_str(&format!(
+            "  {:#05x}  {:04x}  {}\n",
+            addr,
+            opcode,
+            disassemble_instruction(*opcode)
+        ));
+    }
+
+    let addr = error.addr();
+    let start = addr.saturating_sub(DISASSEMBLY_WINDOW * 2);
+    let end = (addr + (DISASSEMBLY_WINDOW + 1) * 2).min(MEM_SIZE);
+    let marker = format!("{:#05x} ", addr);
+
+    out.push_str("\nDisassembly\n");
+    for line in disassemble(&cpu.memory, start, end, None).lines() {
+        let prefix = if line.starts_with(&marker) {
+            "> "
+        } else {
+            "  "
+        };
+        out.push_str(&format!("{}{}\n", prefix, line));
+    }
+
+    out
+}
+
+// Writes the report and a screenshot in the palette in use to the working
+// directory, returns the report path
+pub fn write(cpu: &CPU, error: &CpuError, palette: &Palette) -> io::Result<String> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs());
+    let path = format!("crash-{}.txt", time);
+    let screenshot_path = format!("crash-{}.png", time);
+
+    let mut report = report(cpu, error);
+    report.push_str(&format!("\nScreenshot: {}\n", screenshot_path));
+    fs::write(&path, report)?;
+
+    let (width, height) = (cpu.framebuffer.width(), cpu.framebuffer.height());
+    let frame = video_driver::render(&cpu.framebuffer, palette);
+    let scaled = image::scale(&frame, width, height, SCREENSHOT_SCALE);
+    image::write_png(
+        &screenshot_path,
+        width * SCREENSHOT_SCALE,
+        height * SCREENSHOT_SCALE,
+        &scaled,
+    )?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_report() {
+        let mut cpu = CPU::new(Config::default());
+        // V0 = 5, call 0x206, (unused), unknown opcode
+        cpu.load_program(vec![0x60, 0x05, 0x22, 0x06, 0x00, 0x00, 0xF0, 0xFF]);
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let error = cpu.step().unwrap_err();
+
+        assert_eq!(
+            error,
+            CpuError::UnknownOpcode {
+                opcode: 0xF0FF,
+                addr: 0x206
+            }
+        );
+
+        let report = report(&cpu, &error);
+        assert!(report.contains("Error: Unknown opcode 0xf0ff @ pc = 0x206"));
+        assert!(report.contains("V0 0x05"));
+        assert!(report.contains("  [0] 0x204"));
+        assert!(report.contains("  0x202  2206  CALL 0x206"));
+        assert!(report.contains("> 0x206  f0ff  ???"));
+    }
+}
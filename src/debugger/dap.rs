@@ -0,0 +1,819 @@
+/*
+Debug Adapter Protocol server, lets editors like VS Code launch and debug ROMs.
+
+Messages are JSON bodies framed with a Content-Length header, exchanged over
+stdio or a single localhost TCP connection. The emulator runs headless while
+being debugged, at the ROM's speed with timers ticking at 60hz.
+
+The platform, quirks and speed come from the ROM database and settings files
+like when playing, and can be overridden in the launch configuration:
+
+    "platform": "schip", "quirks": { "shift": false }, "tickrate": 30
+
+https://microsoft.github.io/debug-adapter-protocol/specification
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use super::source_map::SourceMap;
+use crate::config::Platform;
+use crate::constants::*;
+use crate::cpu::CPU;
+use crate::database::{self, Database};
+use crate::drivers::rom_driver::{Program, ProgramType};
+use crate::drivers::video_driver::{Display, HeadlessDisplay};
+use crate::palette::DEFAULT_PALETTE;
+use crate::settings::{self, Settings};
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const STACK_REF: i64 = 2;
+const MEMORY_REF: i64 = 3;
+const SCREEN_REF: i64 = 4;
+const MEMORY_ROW: usize = 16;
+const FRAME_TIME: Duration = Duration::from_micros(16666);
+
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+            continue;
+        }
+
+        if let Some(len) = header.strip_prefix("Content-Length:") {
+            content_length = len.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; content_length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+pub fn serve_stdio() -> io::Result<()> {
+    serve(io::stdin(), io::stdout())
+}
+
+pub fn serve_tcp(port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for debugger on 127.0.0.1:{}", port);
+
+    let (stream, _) = listener.accept()?;
+    serve(stream.try_clone()?, stream)
+}
+
+// Requests are read on their own thread so a running program can be paused
+fn serve<R, W>(reader: R, writer: W) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    DapServer::new(writer).run(rx)
+}
+
+enum StepMode {
+    Instruction,
+    // Stop once the stack is back to this depth, stepping over calls
+    Over(usize),
+    // Stop once the stack is shallower than this depth
+    Out(usize),
+}
+
+struct Session {
+    cpu: CPU,
+    display: HeadlessDisplay,
+    source_map: Option<SourceMap>,
+    source_breakpoints: HashMap<String, Vec<usize>>,
+    instruction_breakpoints: HashSet<usize>,
+    stop_on_entry: bool,
+    running: bool,
+    step: Option<StepMode>,
+    // Set when resuming so the breakpoint we are stopped on isn't hit again
+    skip_breakpoint: bool,
+    // Instructions run per 60hz frame
+    tickrate: usize,
+    // Instructions run since the timers last ticked, kept across steps so
+    // stepping doesn't run the timers faster than the program
+    frame_instructions: usize,
+}
+
+impl Session {
+    fn breakpoint_at(&self, addr: usize) -> bool {
+        self.instruction_breakpoints.contains(&addr)
+            || self
+                .source_breakpoints
+                .values()
+                .any(|addrs| addrs.contains(&addr))
+    }
+
+    fn step_done(&self) -> bool {
+        let depth = self.cpu.stack.len();
+        match self.step {
+            Some(StepMode::Instruction) => true,
+            Some(StepMode::Over(target)) => depth <= target,
+            Some(StepMode::Out(target)) => depth < target,
+            None => false,
+        }
+    }
+
+    fn resume(&mut self, step: Option<StepMode>) {
+        self.step = step;
+        self.running = true;
+        self.skip_breakpoint = true;
+    }
+}
+
+pub struct DapServer<W: Write> {
+    writer: W,
+    seq: i64,
+    session: Option<Session>,
+    // Where the ROM database and settings files are read from
+    config_dir: Option<PathBuf>,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(writer: W) -> Self {
+        DapServer {
+            writer,
+            seq: 0,
+            session: None,
+            config_dir: settings::config_dir(),
+        }
+    }
+
+    fn run(&mut self, requests: Receiver<Value>) -> io::Result<()> {
+        loop {
+            let running = self.session.as_ref().is_some_and(|s| s.running);
+
+            // Only block waiting for requests while the program is stopped
+            let request = if running {
+                match requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => return Ok(()),
+                }
+            };
+
+            if let Some(request) = request {
+                if !self.handle_request(&request)? {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            let frame_start = Instant::now();
+            self.run_frame()?;
+            if let Some(remaining) = FRAME_TIME.checked_sub(frame_start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+    }
+
+    // Runs one 60hz frame worth of instructions, stopping early on breakpoints and steps
+    fn run_frame(&mut self) -> io::Result<()> {
+        let reason = match self.session.as_mut() {
+            Some(session) => {
+                let mut reason = None;
+
+                for _ in 0..session.tickrate {
+                    if !session.skip_breakpoint && session.breakpoint_at(session.cpu.pc) {
+                        reason = Some(("breakpoint", None));
+                        break;
+                    }
+                    session.skip_breakpoint = false;
+
+                    // The failing instruction is left at pc so it can be inspected
+                    if let Err(e) = session.cpu.step() {
+                        reason = Some(("exception", Some(e.to_string())));
+                        break;
+                    }
+
+                    session.frame_instructions += 1;
+                    if session.frame_instructions >= session.tickrate {
+                        session.frame_instructions = 0;
+                        session.cpu.tick_timers();
+                    }
+
+                    if session.step_done() {
+                        reason = Some(("step", None));
+                        break;
+                    }
+                }
+
+                if session.cpu.take_screen_update() {
+                    session
+                        .display
+                        .present_framebuffer(&session.cpu.framebuffer, &DEFAULT_PALETTE);
+                }
+                reason
+            }
+            None => None,
+        };
+
+        match reason {
+            Some((reason, description)) => self.stop(reason, description),
+            None => Ok(()),
+        }
+    }
+
+    fn stop(&mut self, reason: &str, description: Option<String>) -> io::Result<()> {
+        if let Some(session) = self.session.as_mut() {
+            session.running = false;
+            session.step = None;
+        }
+
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+            body["text"] = json!(description);
+        }
+
+        self.send_event("stopped", body)
+    }
+
+    // Returns false once the client has disconnected
+    fn handle_request(&mut self, request: &Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => self.session().map(|_| json!({})),
+            "threads" => Ok(json!({
+                "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }]
+            })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+                    {
+                        "name": "Memory",
+                        "variablesReference": MEMORY_REF,
+                        "indexedVariables": MEM_SIZE / MEMORY_ROW,
+                        "expensive": true,
+                    },
+                    {
+                        "name": "Screen",
+                        "variablesReference": SCREEN_REF,
+                        "expensive": false,
+                    },
+                ]
+            })),
+            "variables" => self.variables(args),
+            "continue" => self.session().map(|session| {
+                session.resume(None);
+                json!({ "allThreadsContinued": true })
+            }),
+            "next" => self.session().map(|session| {
+                let depth = session.cpu.stack.len();
+                session.resume(Some(StepMode::Over(depth)));
+                json!({})
+            }),
+            "stepIn" => self.session().map(|session| {
+                session.resume(Some(StepMode::Instruction));
+                json!({})
+            }),
+            "stepOut" => self.session().map(|session| {
+                let depth = session.cpu.stack.len();
+                session.resume(Some(StepMode::Out(depth)));
+                json!({})
+            }),
+            "pause" => self.session().map(|_| json!({})),
+            "disconnect" | "terminate" => Ok(json!({})),
+            _ => Err(format!("Unsupported request '{}'", command)),
+        };
+
+        let success = result.is_ok();
+        self.respond(request, result)?;
+
+        if success {
+            match command {
+                "launch" => self.send_event("initialized", json!({}))?,
+                "configurationDone" => {
+                    let session = self.session.as_mut().unwrap();
+                    if session.stop_on_entry {
+                        self.stop("entry", None)?;
+                    } else {
+                        session.running = true;
+                    }
+                }
+                "pause" => self.stop("pause", None)?,
+                "disconnect" | "terminate" => {
+                    self.send_event("terminated", json!({}))?;
+                    return Ok(false);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn session(&mut self) -> Result<&mut Session, String> {
+        self.session
+            .as_mut()
+            .ok_or_else(|| "No program has been launched".to_string())
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("Launch configuration needs a 'program' path")?;
+
+        let path = Path::new(program);
+
+        let program =
+            Program::load(ProgramType::Path(program.to_string())).map_err(|e| e.to_string())?;
+        let config_dir = self.config_dir.as_deref();
+        let metadata = match Database::load_from(config_dir) {
+            Ok(database) => program.metadata(&database),
+            Err(e) => {
+                eprintln!("Warning: unable to read the ROM database, {}", e);
+                None
+            }
+        };
+        let profile = database::profile(metadata.as_ref(), Some(path));
+        let global = config_dir.map(|dir| dir.join("config.toml"));
+        let settings = Settings::load_for_rom(
+            config_dir,
+            global.as_deref(),
+            profile,
+            &program.name,
+            Some(path),
+        )?
+        .merge(launch_settings(args)?);
+
+        let (platform, config, tickrate) = settings.machine()?;
+        program.check(platform).map_err(|e| e.to_string())?;
+
+        let source_map = match args["sourceMap"].as_str() {
+            Some(path) => Some(SourceMap::load(path)?),
+            None => None,
+        };
+
+        let mut cpu = CPU::new(config);
+        cpu.load_program(program.bytes);
+        if platform == Platform::Hires {
+            cpu.start_hires();
+        }
+
+        let mut display = HeadlessDisplay::default();
+        display.present_framebuffer(&cpu.framebuffer, &DEFAULT_PALETTE);
+
+        self.session = Some(Session {
+            cpu,
+            display,
+            source_map,
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: HashSet::new(),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            running: false,
+            step: None,
+            skip_breakpoint: false,
+            tickrate,
+            frame_instructions: 0,
+        });
+
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let path = args["source"]["path"].as_str().unwrap_or("").to_string();
+
+        let mut addrs = Vec::new();
+        let mut breakpoints = Vec::new();
+
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as u32;
+            let found = match &session.source_map {
+                Some(map) => map.addresses_for(&path, line),
+                None => Vec::new(),
+            };
+
+            breakpoints.push(if found.is_empty() {
+                json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No instruction is mapped to this line",
+                })
+            } else {
+                json!({ "verified": true, "line": line })
+            });
+            addrs.extend(found);
+        }
+
+        session.source_breakpoints.insert(path, addrs);
+
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        session.instruction_breakpoints.clear();
+
+        let mut breakpoints = Vec::new();
+
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"].as_str().unwrap_or("");
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+
+            let addr = i64::from_str_radix(reference.trim_start_matches("0x"), 16)
+                .ok()
+                .map(|addr| addr + offset)
+                .filter(|addr| (0..MEM_SIZE as i64).contains(addr));
+
+            match addr {
+                Some(addr) => {
+                    session.instruction_breakpoints.insert(addr as usize);
+                    breakpoints.push(json!({ "verified": true }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "message": "Not a valid memory address",
+                })),
+            }
+        }
+
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // The current instruction followed by the call site of each return address on the stack
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let session = self.session()?;
+        let cpu = &session.cpu;
+
+        let mut addrs = vec![cpu.pc];
+        addrs.extend(
+            cpu.stack
+                .iter()
+                .rev()
+                .map(|addr| (*addr as usize).saturating_sub(2)),
+        );
+
+        let frames: Vec<Value> = addrs
+            .iter()
+            .enumerate()
+            .map(|(id, addr)| stack_frame(session.source_map.as_ref(), id, *addr))
+            .collect();
+
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let cpu = &session.cpu;
+
+        let variables = match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REF) => {
+                let mut registers: Vec<Value> = cpu
+                    .reg_v
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| variable(format!("V{:X}", i), format!("{:#04x}", v)))
+                    .collect();
+
+                registers.push(variable("I".to_string(), format!("{:#05x}", cpu.reg_i)));
+                registers.push(variable("PC".to_string(), format!("{:#05x}", cpu.pc)));
+                registers.push(variable("DT".to_string(), cpu.delay_timer.to_string()));
+                registers.push(variable("ST".to_string(), cpu.sound_timer.to_string()));
+                registers
+            }
+            Some(STACK_REF) => cpu
+                .stack
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, addr)| variable(format!("[{}]", i), format!("{:#05x}", addr)))
+                .collect(),
+            Some(MEMORY_REF) => {
+                let rows = MEM_SIZE / MEMORY_ROW;
+                let start = (args["start"].as_u64().unwrap_or(0) as usize).min(rows);
+                let count = args["count"].as_u64().map_or(rows, |c| c as usize);
+
+                (start..start.saturating_add(count).min(rows))
+                    .map(|row| {
+                        let addr = row * MEMORY_ROW;
+                        let bytes: Vec<String> = cpu.memory[addr..addr + MEMORY_ROW]
+                            .iter()
+                            .map(|b| format!("{:02x}", b))
+                            .collect();
+                        variable(format!("{:#05x}", addr), bytes.join(" "))
+                    })
+                    .collect()
+            }
+            Some(SCREEN_REF) => {
+                let display = &session.display;
+                let width = display.width as usize;
+
+                display
+                    .frame
+                    .chunks(width.max(1) * 3)
+                    .enumerate()
+                    .map(|(y, row)| {
+                        let pixels: String = row
+                            .chunks(3)
+                            .map(|p| {
+                                if p.iter().any(|c| *c > 0) {
+                                    '█'
+                                } else {
+                                    '·'
+                                }
+                            })
+                            .collect();
+                        variable(format!("{:02}", y), pixels)
+                    })
+                    .collect()
+            }
+            _ => return Err("Unknown variables reference".to_string()),
+        };
+
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+
+        self.send(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.writer, &message)
+    }
+}
+
+// Platform, quirks and tickrate set in the launch configuration
+fn launch_settings(args: &Value) -> Result<Settings, String> {
+    let mut settings = Settings {
+        platform: args["platform"].as_str().map(String::from),
+        ..Settings::default()
+    };
+    for (quirk, on) in args["quirks"].as_object().into_iter().flatten() {
+        let on = on
+            .as_bool()
+            .ok_or_else(|| format!("quirks.{}: expected true or false", quirk))?;
+        settings.quirks.insert(quirk.to_lowercase(), on);
+    }
+    settings.timing.tickrate = args["tickrate"].as_u64().map(|tickrate| tickrate as usize);
+
+    settings.validate()?;
+    Ok(settings)
+}
+
+fn stack_frame(source_map: Option<&SourceMap>, id: usize, addr: usize) -> Value {
+    let name = source_map
+        .and_then(|map| map.label_for(addr))
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:#05x}", addr));
+
+    let mut frame = json!({
+        "id": id,
+        "name": name,
+        "line": 0,
+        "column": 0,
+        "instructionPointerReference": format!("{:#05x}", addr),
+    });
+
+    if let Some(location) = source_map.and_then(|map| map.location(addr)) {
+        let file_name = Path::new(&location.file)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+
+        frame["source"] = json!({ "name": file_name, "path": location.file });
+        frame["line"] = json!(location.line);
+        frame["column"] = json!(1);
+    }
+
+    frame
+}
+
+fn variable(name: String, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigFlags;
+    use std::fs;
+
+    fn request(server: &mut DapServer<Vec<u8>>, command: &str, arguments: Value) {
+        let request =
+            json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments });
+        assert!(server.handle_request(&request).unwrap());
+    }
+
+    // Launches the program from a file only this test run uses, ignoring the
+    // user's settings and ROM database
+    fn launch_with(name: &str, program: &[u8], mut arguments: Value) -> DapServer<Vec<u8>> {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        fs::write(&path, program).unwrap();
+
+        let mut server = DapServer::new(Vec::new());
+        server.config_dir = None;
+        request(&mut server, "initialize", json!({}));
+        arguments["program"] = json!(path.to_str().unwrap());
+        request(&mut server, "launch", arguments);
+        fs::remove_file(path).unwrap();
+        server
+    }
+
+    fn launch(name: &str, program: &[u8]) -> DapServer<Vec<u8>> {
+        let mut server = launch_with(name, program, json!({ "stopOnEntry": true }));
+        request(&mut server, "configurationDone", json!({}));
+        server
+    }
+
+    fn run_until_stopped(server: &mut DapServer<Vec<u8>>) {
+        while server.session.as_ref().unwrap().running {
+            server.run_frame().unwrap();
+        }
+    }
+
+    // call 0x206, jump 0x202, (unused), V0 = 5, return
+    const PROGRAM: [u8; 10] = [0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x60, 0x05, 0x00, 0xEE];
+
+    #[test]
+    fn test_message_framing() {
+        let message = json!({ "seq": 1, "type": "request", "command": "threads" });
+
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &message).unwrap();
+        write_message(&mut buffer, &message).unwrap();
+
+        let mut reader = io::Cursor::new(buffer);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message.clone()));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_step_over_and_in() {
+        let mut server = launch("dap_test_step.ch8", &PROGRAM);
+        assert_eq!(server.session.as_ref().unwrap().cpu.pc, 0x200);
+
+        request(&mut server, "next", json!({ "threadId": THREAD_ID }));
+        run_until_stopped(&mut server);
+
+        let cpu = &server.session.as_ref().unwrap().cpu;
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.reg_v[0], 5);
+
+        let mut server = launch("dap_test_step_in.ch8", &PROGRAM);
+        request(&mut server, "stepIn", json!({ "threadId": THREAD_ID }));
+        run_until_stopped(&mut server);
+
+        let cpu = &server.session.as_ref().unwrap().cpu;
+        assert_eq!(cpu.pc, 0x206);
+        assert_eq!(cpu.stack, vec![0x202]);
+    }
+
+    #[test]
+    fn test_exception_stops() {
+        // return with an empty stack
+        let mut server = launch("dap_test_exception.ch8", &[0x00, 0xEE]);
+
+        request(&mut server, "continue", json!({ "threadId": THREAD_ID }));
+        run_until_stopped(&mut server);
+
+        assert_eq!(server.session.as_ref().unwrap().cpu.pc, 0x200);
+        let output = String::from_utf8(server.writer.clone()).unwrap();
+        assert!(output.contains(r#""reason":"exception""#));
+    }
+
+    #[test]
+    fn test_launch_settings() {
+        let server = launch_with(
+            "dap_test_launch.sc8",
+            &PROGRAM,
+            json!({ "quirks": { "shift": false }, "tickrate": 2 }),
+        );
+        let session = server.session.as_ref().unwrap();
+        assert_eq!(session.tickrate, 2);
+        assert!(!session.cpu.config.flag_set(ConfigFlags::Shift));
+        // From the .sc8 extension
+        assert!(session.cpu.config.flag_set(ConfigFlags::JumpWithOffset));
+
+        let error = launch_settings(&json!({ "platform": "megachip" })).unwrap_err();
+        assert!(error.starts_with("platform:"));
+    }
+
+    #[test]
+    fn test_memory_variables() {
+        let mut server = launch("dap_test_memory.ch8", &PROGRAM);
+
+        request(
+            &mut server,
+            "variables",
+            json!({ "variablesReference": MEMORY_REF, "start": 255, "count": u64::MAX }),
+        );
+        let output = String::from_utf8(server.writer.clone()).unwrap();
+        assert!(output.contains(r#""name":"0xff0""#));
+        assert!(output.contains(r#""success":true"#));
+    }
+
+    #[test]
+    fn test_timers_tick_per_frame() {
+        // V0 = 5, DT = V0, loop forever
+        let mut server = launch("dap_test_timers.ch8", &[0x60, 0x05, 0xF0, 0x15, 0x12, 0x04]);
+
+        for _ in 0..3 {
+            request(&mut server, "stepIn", json!({ "threadId": THREAD_ID }));
+            run_until_stopped(&mut server);
+        }
+        assert_eq!(server.session.as_ref().unwrap().cpu.delay_timer, 5);
+
+        request(&mut server, "continue", json!({ "threadId": THREAD_ID }));
+        server.run_frame().unwrap();
+        assert_eq!(server.session.as_ref().unwrap().cpu.delay_timer, 4);
+    }
+
+    #[test]
+    fn test_instruction_breakpoint() {
+        let mut server = launch("dap_test_breakpoint.ch8", &PROGRAM);
+
+        request(
+            &mut server,
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [{ "instructionReference": "0x208" }] }),
+        );
+        request(&mut server, "continue", json!({ "threadId": THREAD_ID }));
+        run_until_stopped(&mut server);
+
+        let cpu = &server.session.as_ref().unwrap().cpu;
+        assert_eq!(cpu.pc, 0x208);
+        assert_eq!(cpu.reg_v[0], 5);
+    }
+}
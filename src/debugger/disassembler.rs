@@ -0,0 +1,216 @@
+/*
+Turns memory back into readable instructions.
+
+Without coverage every pair of bytes is decoded as an instruction. With a
+coverage map, bytes that were only ever read or written are listed as data,
+and instructions that never ran are marked so dead code stands out.
+*/
+
+use std::collections::BTreeMap;
+
+use super::coverage::{Coverage, EXECUTED, INSTRUCTION, READ, WRITTEN};
+use crate::constants::PROGRAM_START;
+
+const DATA_PER_LINE: usize = 8;
+
+pub fn disassemble_instruction(opcode: u16) -> String {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+    let nnn = opcode & 0x0FFF;
+
+    match (opcode >> 12, n) {
+        (0x0, _) => match opcode {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            0x00FE => "LOW".to_string(),
+            0x00FF => "HIGH".to_string(),
+            _ => format!("SYS {:#05x}", nnn),
+        },
+        (0x1, _) => format!("JP {:#05x}", nnn),
+        (0x2, _) => format!("CALL {:#05x}", nnn),
+        (0x3, _) => format!("SE V{:X}, {:#04x}", x, nn),
+        (0x4, _) => format!("SNE V{:X}, {:#04x}", x, nn),
+        (0x5, 0x0) => format!("SE V{:X}, V{:X}", x, y),
+        (0x6, _) => format!("LD V{:X}, {:#04x}", x, nn),
+        (0x7, _) => format!("ADD V{:X}, {:#04x}", x, nn),
+        (0x8, 0x0) => format!("LD V{:X}, V{:X}", x, y),
+        (0x8, 0x1) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8, 0x2) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8, 0x3) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8, 0x4) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8, 0x5) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8, 0x6) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x8, 0x7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8, 0xE) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x9, 0x0) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA, _) => format!("LD I, {:#05x}", nnn),
+        (0xB, _) => format!("JP V0, {:#05x}", nnn),
+        (0xC, _) => format!("RND V{:X}, {:#04x}", x, nn),
+        (0xD, _) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _) if nn == 0x9E => format!("SKP V{:X}", x),
+        (0xE, _) if nn == 0xA1 => format!("SKNP V{:X}", x),
+        (0xF, _) => match nn {
+            0x01 => format!("PLANE {}", x),
+            0x02 if x == 0 => "AUDIO".to_string(),
+            0x3A => format!("PITCH V{:X}", x),
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            _ => "???".to_string(),
+        },
+        _ => "???".to_string(),
+    }
+}
+
+// Disassembles memory[start..end], one line per instruction or row of data
+pub fn disassemble(memory: &[u8], start: usize, end: usize, coverage: Option<&Coverage>) -> String {
+    let end = end.min(memory.len());
+    let mut out = String::new();
+    let mut addr = start;
+
+    while addr < end {
+        let flags = coverage.map(|c| c.flags(addr));
+        let next_flags = coverage.map_or(0, |c| c.flags(addr + 1));
+
+        match flags {
+            Some(flags) if flags & INSTRUCTION == 0 && flags & (READ | WRITTEN | EXECUTED) != 0 => {
+                // Data runs until the next instruction or untouched byte
+                let mut bytes = Vec::new();
+                while addr < end && bytes.len() < DATA_PER_LINE {
+                    let flags = coverage.unwrap().flags(addr);
+                    if flags & INSTRUCTION != 0 || flags & (READ | WRITTEN | EXECUTED) == 0 {
+                        break;
+                    }
+                    bytes.push(memory[addr]);
+                    addr += 1;
+                }
+                out.push_str(&data_line(addr - bytes.len(), &bytes));
+            }
+            _ if addr + 1 >= end || next_flags & INSTRUCTION != 0 => {
+                // A lone byte in front of an instruction can't be code
+                out.push_str(&data_line(addr, &memory[addr..addr + 1]));
+                addr += 1;
+            }
+            _ => {
+                let opcode = (memory[addr] as u16) << 8 | memory[addr + 1] as u16;
+                let mut line = format!(
+                    "{:#05x}  {:04x}  {}",
+                    addr,
+                    opcode,
+                    disassemble_instruction(opcode)
+                );
+                if flags == Some(0) {
+                    line = format!("{:<36}; never executed", line);
+                }
+                out.push_str(&line);
+                out.push('\n');
+                addr += 2;
+            }
+        }
+    }
+
+    out
+}
+
+// Address and opcode of every instruction reachable from the start of a
+// program by following jumps, calls and skips. Computed jumps (BNNN) can't be
+// followed, so code only reached through them is missed
+pub fn reachable_instructions(program: &[u8]) -> Vec<(usize, u16)> {
+    let end = PROGRAM_START + program.len();
+    let mut seen = BTreeMap::new();
+    let mut pending = vec![PROGRAM_START];
+
+    while let Some(addr) = pending.pop() {
+        if addr < PROGRAM_START || addr + 1 >= end || seen.contains_key(&addr) {
+            continue;
+        }
+        let offset = addr - PROGRAM_START;
+        let opcode = (program[offset] as u16) << 8 | program[offset + 1] as u16;
+        seen.insert(addr, opcode);
+
+        let nnn = (opcode & 0x0FFF) as usize;
+        match (opcode >> 12, opcode & 0x00FF) {
+            (0x0, 0xEE | 0xFD) | (0xB, _) => {}
+            (0x1, _) => pending.push(nnn),
+            (0x2, _) => pending.extend([nnn, addr + 2]),
+            (0x3 | 0x4 | 0x5 | 0x9, _) | (0xE, 0x9E | 0xA1) => {
+                // Skipping F000 NNNN skips all four of its bytes
+                let long = program.get(offset + 2..offset + 4) == Some(&[0xF0, 0x00]);
+                pending.extend([addr + 2, if long { addr + 6 } else { addr + 4 }]);
+            }
+            // F000 NNNN is four bytes long
+            (0xF, 0x00) if opcode == 0xF000 => pending.push(addr + 4),
+            _ => pending.push(addr + 2),
+        }
+    }
+
+    seen.into_iter().collect()
+}
+
+fn data_line(addr: usize, bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:#04x}", b)).collect();
+    format!("{:#05x}        db {}\n", addr, bytes.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_reachable_instructions() {
+        // CALL 0x208; JP 0x206; db 0xff, 0xff; SE V0, 0; RET; db 0xff
+        let program = [
+            0x22, 0x08, 0x12, 0x06, 0xFF, 0xFF, 0x12, 0x06, 0x30, 0x00, 0x00, 0xEE, 0xFF,
+        ];
+        let addrs: Vec<usize> = reachable_instructions(&program)
+            .iter()
+            .map(|(addr, _)| *addr)
+            .collect();
+        assert_eq!(addrs, vec![0x200, 0x202, 0x206, 0x208, 0x20a]);
+    }
+
+    #[test]
+    fn test_disassemble_instruction() {
+        assert_eq!(disassemble_instruction(0x00E0), "CLS");
+        assert_eq!(disassemble_instruction(0x22A0), "CALL 0x2a0");
+        assert_eq!(disassemble_instruction(0x8AB4), "ADD VA, VB");
+        assert_eq!(disassemble_instruction(0xD125), "DRW V1, V2, 5");
+        assert_eq!(disassemble_instruction(0xF355), "LD [I], V3");
+        assert_eq!(disassemble_instruction(0xF3FF), "???");
+    }
+
+    #[test]
+    fn test_disassemble_with_coverage() {
+        let mut cpu = CPU::new(Config::default());
+        cpu.trace_memory = true;
+        // I = 0x208, draw V0 V0 1, jump 0x204, (never executed), sprite
+        let program = vec![0xA2, 0x08, 0xD0, 0x01, 0x12, 0x04, 0x00, 0xE0, 0xFF];
+        cpu.load_program(program.clone());
+
+        let mut coverage = Coverage::new(program.len());
+        for _ in 0..4 {
+            cpu.step().unwrap();
+            coverage.record(&cpu);
+        }
+
+        let end = PROGRAM_START + program.len();
+        let listing = disassemble(&cpu.memory, PROGRAM_START, end, Some(&coverage));
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "0x200  a208  LD I, 0x208");
+        assert_eq!(lines[2], "0x204  1204  JP 0x204");
+        assert!(lines[3].starts_with("0x206  00e0  CLS"));
+        assert!(lines[3].ends_with("; never executed"));
+        assert_eq!(lines[4], "0x208        db 0xff");
+    }
+}
@@ -0,0 +1,109 @@
+/*
+Live memory access heatmap.
+
+Read, write and execute counts are kept per address and decay every frame, so
+the heatmap shows where a game is working right now. Each of the 4096 bytes is
+one pixel of a 64x64 image: executes are green, reads blue and writes red.
+*/
+
+use crate::constants::*;
+use crate::cpu::{AccessKind, CPU};
+
+pub const HEATMAP_SIZE: u32 = 64;
+pub const HEATMAP_SCALE: u32 = 8;
+
+// Fraction of the heat kept each frame, roughly a 0.2 second half life at 60hz
+const DECAY: f32 = 0.95;
+// Heat at which a channel is about 63% bright
+const SATURATION: f32 = 4.0;
+
+pub struct Heatmap {
+    executes: Vec<f32>,
+    reads: Vec<f32>,
+    writes: Vec<f32>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Heatmap {
+            executes: vec![0.0; MEM_SIZE],
+            reads: vec![0.0; MEM_SIZE],
+            writes: vec![0.0; MEM_SIZE],
+        }
+    }
+
+    // Records the memory accessed by the last executed instruction
+    pub fn record(&mut self, cpu: &CPU) {
+        for access in &cpu.accesses {
+            let addr = access.addr % MEM_SIZE;
+            match access.kind {
+                AccessKind::Execute => {
+                    self.executes[addr] += 1.0;
+                    self.executes[(addr + 1) % MEM_SIZE] += 1.0;
+                }
+                AccessKind::Read => self.reads[addr] += 1.0,
+                AccessKind::Write => self.writes[addr] += 1.0,
+            }
+        }
+    }
+
+    // Should be called once per frame
+    pub fn decay(&mut self) {
+        for heat in self
+            .executes
+            .iter_mut()
+            .chain(self.reads.iter_mut())
+            .chain(self.writes.iter_mut())
+        {
+            *heat *= DECAY;
+        }
+    }
+
+    // RGB24 pixels, one per address
+    pub fn render(&self) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(MEM_SIZE * 3);
+
+        for addr in 0..MEM_SIZE {
+            pixels.push(brightness(self.writes[addr]));
+            pixels.push(brightness(self.executes[addr]));
+            pixels.push(brightness(self.reads[addr]));
+        }
+
+        pixels
+    }
+}
+
+fn brightness(heat: f32) -> u8 {
+    ((1.0 - (-heat / SATURATION).exp()) * 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_record_and_decay() {
+        let mut cpu = CPU::new(Config::default());
+        cpu.trace_memory = true;
+        // I = 0x300, store V0
+        cpu.load_program(vec![0xA3, 0x00, 0xF0, 0x55]);
+
+        let mut heatmap = Heatmap::new();
+        for _ in 0..2 {
+            cpu.step().unwrap();
+            heatmap.record(&cpu);
+        }
+
+        let pixels = heatmap.render();
+        assert!(pixels[0x200 * 3 + 1] > 0);
+        assert!(pixels[0x300 * 3] > 0);
+        assert_eq!(pixels[0x300 * 3 + 2], 0);
+        assert_eq!(pixels[0x400 * 3 + 1], 0);
+
+        for _ in 0..200 {
+            heatmap.decay();
+        }
+        assert!(heatmap.render().iter().all(|p| *p == 0));
+    }
+}
@@ -0,0 +1,9 @@
+pub mod coverage;
+pub mod crash_dump;
+pub mod dap;
+pub mod disassembler;
+#[cfg(feature = "sdl")]
+pub mod heatmap;
+pub mod profiler;
+pub mod source_map;
+pub mod trace;
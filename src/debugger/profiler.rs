@@ -0,0 +1,292 @@
+/*
+Execution profiler, counts where a ROM spends its cycles.
+
+Every executed instruction is counted per address and per opcode class, and
+attributed to the subroutine it runs in by following 2NNN calls and 00EE
+returns on a shadow call stack. The report lists hotspots, opcode classes and
+inclusive/exclusive subroutine cost, the folded stacks can be rendered with
+flamegraph.pl or inferno-flamegraph.
+*/
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use crate::constants::*;
+use crate::cpu::CPU;
+
+const HOTSPOTS: usize = 20;
+
+#[derive(Default)]
+struct Subroutine {
+    calls: u64,
+    inclusive: u64,
+    exclusive: u64,
+}
+
+pub struct Profiler {
+    total: u64,
+    by_addr: HashMap<usize, u64>,
+    by_class: HashMap<&'static str, u64>,
+    subroutines: HashMap<usize, Subroutine>,
+    folded: HashMap<Vec<usize>, u64>,
+    // Entry addresses of the subroutines currently being executed
+    call_stack: Vec<usize>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        let mut subroutines = HashMap::new();
+        subroutines.insert(PROGRAM_START, Subroutine::default());
+
+        Profiler {
+            total: 0,
+            by_addr: HashMap::new(),
+            by_class: HashMap::new(),
+            subroutines,
+            folded: HashMap::new(),
+            call_stack: vec![PROGRAM_START],
+        }
+    }
+
+    // Records the instruction the cpu is about to execute
+    pub fn record(&mut self, cpu: &CPU) {
+        let pc = cpu.pc;
+        // Past the end of memory the step fails with a bad access, nothing runs
+        let (Some(&high), Some(&low)) = (cpu.memory.get(pc), cpu.memory.get(pc + 1)) else {
+            return;
+        };
+        let opcode = (high as u16) << 8 | low as u16;
+
+        self.total += 1;
+        *self.by_addr.entry(pc).or_insert(0) += 1;
+        *self.by_class.entry(opcode_class(opcode)).or_insert(0) += 1;
+
+        let current = *self.call_stack.last().unwrap();
+        self.subroutines.entry(current).or_default().exclusive += 1;
+
+        // Recursive subroutines only count once towards inclusive cost
+        for (i, addr) in self.call_stack.iter().enumerate() {
+            if !self.call_stack[..i].contains(addr) {
+                self.subroutines.entry(*addr).or_default().inclusive += 1;
+            }
+        }
+
+        match self.folded.get_mut(&self.call_stack[..]) {
+            Some(count) => *count += 1,
+            None => {
+                self.folded.insert(self.call_stack.clone(), 1);
+            }
+        }
+
+        // Calls and returns are charged to the caller, the callee starts with the next instruction
+        if opcode & 0xF000 == 0x2000 {
+            let target = (opcode & 0x0FFF) as usize;
+            self.subroutines.entry(target).or_default().calls += 1;
+            self.call_stack.push(target);
+        } else if opcode == 0x00EE && self.call_stack.len() > 1 {
+            self.call_stack.pop();
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = format!("CHIP-8 profile: {} instructions\n", self.total);
+
+        out.push_str("\nHotspots\n");
+        out.push_str(&format!("  {:<8}{:>12}{:>9}\n", "address", "count", "%"));
+        for (addr, count) in sorted_by_count(&self.by_addr).iter().take(HOTSPOTS) {
+            out.push_str(&format!(
+                "  {:<8}{:>12}{:>8.2}%\n",
+                format!("{:#05x}", addr),
+                count,
+                self.percent(*count)
+            ));
+        }
+
+        out.push_str("\nOpcode classes\n");
+        out.push_str(&format!("  {:<8}{:>12}{:>9}\n", "class", "count", "%"));
+        for (class, count) in sorted_by_count(&self.by_class) {
+            out.push_str(&format!(
+                "  {:<8}{:>12}{:>8.2}%\n",
+                class,
+                count,
+                self.percent(count)
+            ));
+        }
+
+        let mut subroutines: Vec<(&usize, &Subroutine)> = self.subroutines.iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+
+        out.push_str("\nSubroutines\n");
+        out.push_str(&format!(
+            "  {:<12}{:>8}{:>12}{:>9}{:>12}{:>9}\n",
+            "name", "calls", "inclusive", "%", "exclusive", "%"
+        ));
+        for (addr, sub) in subroutines {
+            out.push_str(&format!(
+                "  {:<12}{:>8}{:>12}{:>8.2}%{:>12}{:>8.2}%\n",
+                subroutine_name(*addr),
+                sub.calls,
+                sub.inclusive,
+                self.percent(sub.inclusive),
+                sub.exclusive,
+                self.percent(sub.exclusive)
+            ));
+        }
+
+        out
+    }
+
+    // One line per call stack, e.g. "main;sub_0x2a0 1234"
+    pub fn folded_stacks(&self) -> String {
+        let mut lines: Vec<String> = self
+            .folded
+            .iter()
+            .map(|(stack, count)| {
+                let names: Vec<String> = stack.iter().map(|addr| subroutine_name(*addr)).collect();
+                format!("{} {}", names.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+
+    // Writes the report to the path and the folded stacks next to it
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.report())?;
+        fs::write(format!("{}.folded", path), self.folded_stacks())
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        count as f64 * 100.0 / self.total as f64
+    }
+}
+
+fn sorted_by_count<K: Copy + Ord>(counts: &HashMap<K, u64>) -> Vec<(K, u64)> {
+    let mut sorted: Vec<(K, u64)> = counts.iter().map(|(k, v)| (*k, *v)).collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    sorted
+}
+
+fn subroutine_name(addr: usize) -> String {
+    if addr == PROGRAM_START {
+        "main".to_string()
+    } else {
+        format!("sub_{:#05x}", addr)
+    }
+}
+
+// Groups opcodes by their instruction pattern, e.g. 0x8124 -> "8XY4"
+pub fn opcode_class(opcode: u16) -> &'static str {
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+
+    match opcode >> 12 {
+        0x0 => match opcode {
+            0x00E0 => "00E0",
+            0x00EE => "00EE",
+            0x00FE => "00FE",
+            0x00FF => "00FF",
+            _ => "0NNN",
+        },
+        0x1 => "1NNN",
+        0x2 => "2NNN",
+        0x3 => "3XNN",
+        0x4 => "4XNN",
+        0x5 => "5XY0",
+        0x6 => "6XNN",
+        0x7 => "7XNN",
+        0x8 => match n {
+            0x0 => "8XY0",
+            0x1 => "8XY1",
+            0x2 => "8XY2",
+            0x3 => "8XY3",
+            0x4 => "8XY4",
+            0x5 => "8XY5",
+            0x6 => "8XY6",
+            0x7 => "8XY7",
+            0xE => "8XYE",
+            _ => "unknown",
+        },
+        0x9 => "9XY0",
+        0xA => "ANNN",
+        0xB => "BNNN",
+        0xC => "CXNN",
+        0xD => "DXYN",
+        0xE => match nn {
+            0x9E => "EX9E",
+            0xA1 => "EXA1",
+            _ => "unknown",
+        },
+        _ => match nn {
+            0x01 => "FN01",
+            0x02 => "F002",
+            0x3A => "FX3A",
+            0x07 => "FX07",
+            0x0A => "FX0A",
+            0x15 => "FX15",
+            0x18 => "FX18",
+            0x1E => "FX1E",
+            0x29 => "FX29",
+            0x33 => "FX33",
+            0x55 => "FX55",
+            0x65 => "FX65",
+            _ => "unknown",
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_subroutine_attribution() {
+        let mut cpu = CPU::new(Config::default());
+        // call 0x206, jump 0x202, (unused), V0 = 5, return
+        cpu.load_program(vec![
+            0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x60, 0x05, 0x00, 0xEE,
+        ]);
+
+        let mut profiler = Profiler::new();
+        for _ in 0..5 {
+            profiler.record(&cpu);
+            cpu.step().unwrap();
+        }
+
+        // call, V0 = 5, return, jump, jump
+        assert_eq!(profiler.total, 5);
+        assert_eq!(profiler.by_addr[&0x202], 2);
+        assert_eq!(profiler.by_class["2NNN"], 1);
+
+        let main = &profiler.subroutines[&PROGRAM_START];
+        assert_eq!((main.inclusive, main.exclusive), (5, 3));
+
+        let sub = &profiler.subroutines[&0x206];
+        assert_eq!((sub.calls, sub.inclusive, sub.exclusive), (1, 2, 2));
+
+        assert_eq!(profiler.folded_stacks(), "main 3\nmain;sub_0x206 2\n");
+
+        for pc in [MEM_SIZE - 1, MEM_SIZE] {
+            cpu.pc = pc;
+            profiler.record(&cpu);
+        }
+        assert_eq!(profiler.total, 5);
+    }
+
+    #[test]
+    fn test_opcode_class() {
+        assert_eq!(opcode_class(0x00E0), "00E0");
+        assert_eq!(opcode_class(0x8124), "8XY4");
+        assert_eq!(opcode_class(0xD015), "DXYN");
+        assert_eq!(opcode_class(0xF265), "FX65");
+        assert_eq!(opcode_class(0xF2FF), "unknown");
+    }
+}
@@ -0,0 +1,165 @@
+/*
+Maps program addresses back to assembler source lines.
+
+The map is a plain text file with one instruction per line:
+
+    # address  file:line  [label]
+    0x200 game.8o:4 main
+    0x202 game.8o:5
+    0x2a0 lib/sprites.8o:12 draw_player
+
+Addresses may be written with or without the 0x prefix. A label marks the
+start of a subroutine and is used to name stack frames.
+*/
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    locations: BTreeMap<usize, SourceLocation>,
+    labels: BTreeMap<usize, String>,
+}
+
+impl SourceMap {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read source map {}: {}", path, e))?;
+        let mut map = SourceMap::parse(&text)?;
+
+        // Source files are relative to the map itself
+        let full_path = fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e))?;
+        if let Some(dir) = full_path.parent() {
+            for location in map.locations.values_mut() {
+                location.file = dir.join(&location.file).to_string_lossy().into_owned();
+            }
+        }
+
+        Ok(map)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut map = SourceMap::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let (addr, location) = match (parts.next(), parts.next()) {
+                (Some(addr), Some(location)) => (addr, location),
+                _ => {
+                    return Err(format!(
+                        "line {}: expected '<address> <file>:<line>'",
+                        i + 1
+                    ))
+                }
+            };
+
+            let addr = usize::from_str_radix(addr.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("line {}: invalid address '{}'", i + 1, addr))?;
+
+            let (file, source_line) = location
+                .rsplit_once(':')
+                .and_then(|(file, n)| Some((file, n.parse::<u32>().ok()?)))
+                .ok_or_else(|| format!("line {}: invalid location '{}'", i + 1, location))?;
+
+            map.locations.insert(
+                addr,
+                SourceLocation {
+                    file: file.to_string(),
+                    line: source_line,
+                },
+            );
+
+            if let Some(label) = parts.next() {
+                map.labels.insert(addr, label.to_string());
+            }
+        }
+
+        Ok(map)
+    }
+
+    pub fn location(&self, addr: usize) -> Option<&SourceLocation> {
+        self.locations.get(&addr)
+    }
+
+    // Finds the label of the subroutine containing the address
+    pub fn label_for(&self, addr: usize) -> Option<&str> {
+        self.labels
+            .range(..=addr)
+            .next_back()
+            .map(|(_, label)| label.as_str())
+    }
+
+    // Editors send absolute paths, so match on trailing path components
+    pub fn addresses_for(&self, path: &str, line: u32) -> Vec<usize> {
+        let path = Path::new(path);
+        self.locations
+            .iter()
+            .filter(|(_, location)| {
+                location.line == line
+                    && (path.ends_with(&location.file) || Path::new(&location.file).ends_with(path))
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "
+        # test map
+        0x200 game.8o:4 main
+        0x202 game.8o:5
+        2a0 lib/sprites.8o:12 draw_player
+        0x2a2 lib/sprites.8o:12
+    ";
+
+    #[test]
+    fn test_parse() {
+        let map = SourceMap::parse(MAP).unwrap();
+
+        assert_eq!(
+            map.location(0x202),
+            Some(&SourceLocation {
+                file: "game.8o".to_string(),
+                line: 5
+            })
+        );
+        assert_eq!(map.location(0x204), None);
+        assert_eq!(map.label_for(0x202), Some("main"));
+        assert_eq!(map.label_for(0x2a2), Some("draw_player"));
+        assert_eq!(map.label_for(0x100), None);
+    }
+
+    #[test]
+    fn test_addresses_for() {
+        let map = SourceMap::parse(MAP).unwrap();
+
+        assert_eq!(map.addresses_for("/home/dev/game.8o", 4), vec![0x200]);
+        assert_eq!(
+            map.addresses_for("/home/dev/lib/sprites.8o", 12),
+            vec![0x2a0, 0x2a2]
+        );
+        assert!(map.addresses_for("/home/dev/other.8o", 4).is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(SourceMap::parse("0x200").is_err());
+        assert!(SourceMap::parse("zz game.8o:1").is_err());
+        assert!(SourceMap::parse("0x200 game.8o").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+/*
+Instruction trace.
+
+Writes one line per executed instruction with the address, opcode, its
+disassembly and the registers before it runs, so two runs can be diffed to
+find where they part ways.
+*/
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use super::disassembler::disassemble_instruction;
+use crate::cpu::CPU;
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+}
+
+impl Tracer {
+    // "-" traces to stderr
+    pub fn create(path: &str) -> io::Result<Self> {
+        let out: Box<dyn Write> = if path == "-" {
+            Box::new(io::stderr())
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        Ok(Tracer { out })
+    }
+
+    // Records the instruction about to be executed
+    pub fn record(&mut self, cpu: &CPU) -> io::Result<()> {
+        writeln!(self.out, "{}", line(cpu))
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn line(cpu: &CPU) -> String {
+    let pc = cpu.pc;
+    // pc can be past the end of memory, the step then fails with a bad access
+    let byte = |addr: usize| cpu.memory.get(addr).copied().unwrap_or(0) as u16;
+    let opcode = byte(pc) << 8 | byte(pc + 1);
+    let registers: Vec<String> = cpu.reg_v.iter().map(|v| format!("{:02x}", v)).collect();
+
+    format!(
+        "{:#05x}  {:04x}  {:<20} V={} I={:03x} DT={:02x} ST={:02x}",
+        pc,
+        opcode,
+        disassemble_instruction(opcode),
+        registers.join(" "),
+        cpu.reg_i,
+        cpu.delay_timer,
+        cpu.sound_timer
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::constants::*;
+
+    #[test]
+    fn test_line() {
+        let mut cpu = CPU::new(Config::default());
+        cpu.memory[PROGRAM_START..PROGRAM_START + 2].copy_from_slice(&[0x6A, 0x12]);
+        cpu.reg_v[0xA] = 0xFF;
+        cpu.reg_i = 0x123;
+
+        assert_eq!(
+            line(&cpu),
+            "0x200  6a12  LD VA, 0x12          V=00 00 00 00 00 00 00 00 00 00 ff 00 00 00 00 00 \
+             I=123 DT=00 ST=00"
+        );
+
+        cpu.pc = MEM_SIZE;
+        assert!(line(&cpu).starts_with("0x1000  0000"));
+    }
+}
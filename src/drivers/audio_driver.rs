@@ -1,56 +1,588 @@
-use sdl2;
+use crate::constants::DEFAULT_PITCH;
+use hound::{SampleFormat, WavSpec, WavWriter};
+#[cfg(feature = "sdl")]
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use std::fs::File;
+use std::io::{self, BufWriter};
+#[cfg(feature = "sdl")]
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    Arc,
+};
 
-pub struct AudioDriver {
-    device: AudioDevice<SquareWave>,
+pub const SAMPLE_RATE: i32 = 44100;
+// Length of the fade in and out around each beep, long enough to avoid clicks
+pub const RAMP_MS: f32 = 5.0;
+// Emulated frames per second, the sound timer's rate
+const FPS: i32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!(
+                "Unknown waveform '{}', expected square, sine, triangle or noise",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BeepSettings {
+    pub frequency: f32,
+    // 0 to 1
+    pub volume: f32,
+    pub waveform: Waveform,
+}
+
+impl Default for BeepSettings {
+    fn default() -> Self {
+        BeepSettings {
+            frequency: 240.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+        }
+    }
+}
+
+// XO-CHIP audio, 128 1-bit samples played in a loop, most significant bit first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pattern {
+    pub bits: [u8; 16],
+    pub pitch: u8,
+}
+
+impl Pattern {
+    // Bits played per second
+    pub fn rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - DEFAULT_PITCH as f32) / 48.0)
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        (self.bits[index / 8] >> (7 - index % 8)) & 1 == 1
+    }
+}
+
+// What the emulator wants played during a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sound {
+    // Whether the sound timer is running
+    pub beeping: bool,
+    // Replaces the configured waveform once a program loads one
+    pub pattern: Option<Pattern>,
+}
+
+// Generates the beep one sample at a time
+pub struct Tone {
+    pub settings: BeepSettings,
+    pub muted: bool,
+    pub pattern: Option<Pattern>,
+    sample_rate: f32,
+    phase: f32,
+    // Noise holds a random level for each period, from a xorshift generator
+    noise_state: u32,
+    noise_level: f32,
+}
+
+impl Tone {
+    pub fn new(settings: BeepSettings, sample_rate: i32) -> Self {
+        Tone {
+            settings,
+            muted: false,
+            pattern: None,
+            sample_rate: sample_rate as f32,
+            phase: 0.0,
+            noise_state: 0x2545_F491,
+            noise_level: 0.0,
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        if let Some(pattern) = self.pattern {
+            // A phase of 1 is one pass through the whole pattern
+            let level = if pattern.bit((self.phase * 128.0) as usize % 128) {
+                1.0
+            } else {
+                -1.0
+            };
+            self.phase = (self.phase + pattern.rate() / 128.0 / self.sample_rate) % 1.0;
+            return if self.muted {
+                0.0
+            } else {
+                level * self.settings.volume
+            };
+        }
+
+        let level = match self.settings.waveform {
+            Waveform::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (self.phase * std::f32::consts::TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Noise => self.noise_level,
+        };
+
+        self.phase += self.settings.frequency / self.sample_rate;
+        if self.phase >= 1.0 {
+            self.phase %= 1.0;
+            self.noise_state ^= self.noise_state << 13;
+            self.noise_state ^= self.noise_state >> 17;
+            self.noise_state ^= self.noise_state << 5;
+            self.noise_level = self.noise_state as f32 / u32::MAX as f32 * 2.0 - 1.0;
+        }
+
+        if self.muted {
+            0.0
+        } else {
+            level * self.settings.volume
+        }
+    }
 }
 
-impl AudioDriver {
-    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
-        let audio_subsystem = sdl_context.audio().unwrap();
+// Something the beep can be played on
+pub trait Audio {
+    // Called once per emulated frame
+    fn frame(&mut self, sound: Sound);
+
+    // The rest are for the window's hotkeys
+
+    // Silences the beep until the next frame
+    #[cfg(feature = "sdl")]
+    fn pause(&mut self) {}
+
+    #[cfg(feature = "sdl")]
+    fn volume(&mut self) -> f32;
+
+    #[cfg(feature = "sdl")]
+    fn set_volume(&mut self, volume: f32);
+
+    // Returns whether the beep is now muted
+    #[cfg(feature = "sdl")]
+    fn toggle_mute(&mut self) -> bool;
+
+    // Flushes anything written, the sink can't be used afterwards
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The beep's tone shaped by short fades when it starts and stops
+pub struct Beep {
+    tone: Tone,
+    // Current envelope level and how much it moves per sample
+    gain: f32,
+    ramp_step: f32,
+}
+
+impl Beep {
+    pub fn new(tone: Tone) -> Self {
+        let ramp_step = 1000.0 / (RAMP_MS * tone.sample_rate);
+        Beep {
+            tone,
+            gain: 0.0,
+            ramp_step,
+        }
+    }
+
+    pub fn fill(&mut self, sound: Sound, out: &mut [f32]) {
+        if sound.pattern != self.tone.pattern {
+            self.tone.pattern = sound.pattern;
+            self.tone.phase = 0.0;
+        }
+        let target = if sound.beeping { 1.0 } else { 0.0 };
+
+        for x in out.iter_mut() {
+            if self.gain < target {
+                self.gain = (self.gain + self.ramp_step).min(target);
+            } else if self.gain > target {
+                self.gain = (self.gain - self.ramp_step).max(target);
+            }
+
+            *x = if self.gain > 0.0 {
+                self.tone.next_sample() * self.gain
+            } else {
+                // Start every beep at the same point of the wave
+                self.tone.phase = 0.0;
+                0.0
+            };
+        }
+    }
+}
+
+// Sound handed from the emulator to the audio thread without locking. The
+// pattern is written before the flags so a torn read is at most one frame stale
+#[cfg(feature = "sdl")]
+#[derive(Default)]
+struct SharedSound {
+    beeping: AtomicBool,
+    has_pattern: AtomicBool,
+    pattern: [AtomicU64; 2],
+    pitch: AtomicU8,
+}
+
+#[cfg(feature = "sdl")]
+impl SharedSound {
+    fn store(&self, sound: Sound) {
+        if let Some(pattern) = sound.pattern {
+            let (high, low) = pattern.bits.split_at(8);
+            self.pattern[0].store(
+                u64::from_be_bytes(high.try_into().unwrap()),
+                Ordering::Relaxed,
+            );
+            self.pattern[1].store(
+                u64::from_be_bytes(low.try_into().unwrap()),
+                Ordering::Relaxed,
+            );
+            self.pitch.store(pattern.pitch, Ordering::Relaxed);
+        }
+        self.has_pattern
+            .store(sound.pattern.is_some(), Ordering::Release);
+        self.beeping.store(sound.beeping, Ordering::Release);
+    }
+
+    fn load(&self) -> Sound {
+        let beeping = self.beeping.load(Ordering::Acquire);
+        let pattern = self.has_pattern.load(Ordering::Acquire).then(|| {
+            let mut bits = [0; 16];
+            bits[..8].copy_from_slice(&self.pattern[0].load(Ordering::Relaxed).to_be_bytes());
+            bits[8..].copy_from_slice(&self.pattern[1].load(Ordering::Relaxed).to_be_bytes());
+            Pattern {
+                bits,
+                pitch: self.pitch.load(Ordering::Relaxed),
+            }
+        });
+        Sound { beeping, pattern }
+    }
+}
+
+// The device plays continuously, the callback follows the sound set by the
+// emulator instead of the device being paused and resumed
+#[cfg(feature = "sdl")]
+pub struct SdlAudio {
+    device: AudioDevice<SdlCallback>,
+    sound: Arc<SharedSound>,
+}
+
+#[cfg(feature = "sdl")]
+impl SdlAudio {
+    pub fn new(sdl_context: &sdl2::Sdl, settings: BeepSettings) -> Result<Self, String> {
+        let audio_subsystem = sdl_context.audio()?;
 
         let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
+            freq: Some(SAMPLE_RATE),
             channels: Some(1), // mono
             samples: None,     // default sample size
         };
 
-        let device = audio_subsystem
-            .open_playback(None, &desired_spec, |spec| {
-                // initialize the audio callback
-                SquareWave {
-                    phase_inc: 240.0 / spec.freq as f32,
-                    phase: 0.0,
-                    volume: 0.25,
-                }
-            })
-            .unwrap();
+        let sound = Arc::new(SharedSound::default());
+        let device = audio_subsystem.open_playback(None, &desired_spec, |spec| SdlCallback {
+            beep: Beep::new(Tone::new(settings, spec.freq)),
+            sound: sound.clone(),
+        })?;
+        device.resume();
 
-        AudioDriver { device }
+        Ok(SdlAudio { device, sound })
     }
+}
 
-    pub fn start_beep(&self) {
-        self.device.resume();
+#[cfg(feature = "sdl")]
+impl Audio for SdlAudio {
+    fn frame(&mut self, sound: Sound) {
+        self.sound.store(sound);
     }
-    pub fn stop_beep(&self) {
-        self.device.pause();
+
+    fn pause(&mut self) {
+        self.sound.beeping.store(false, Ordering::Release);
+    }
+
+    fn volume(&mut self) -> f32 {
+        self.device.lock().beep.tone.settings.volume
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.device.lock().beep.tone.settings.volume = volume.clamp(0.0, 1.0);
+    }
+
+    fn toggle_mute(&mut self) -> bool {
+        let tone = &mut self.device.lock().beep.tone;
+        tone.muted = !tone.muted;
+        tone.muted
     }
 }
 
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
+#[cfg(feature = "sdl")]
+struct SdlCallback {
+    beep: Beep,
+    sound: Arc<SharedSound>,
 }
 
-impl AudioCallback for SquareWave {
+#[cfg(feature = "sdl")]
+impl AudioCallback for SdlCallback {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            *x = self.volume * if self.phase < 0.5 { 1.0 } else { -1.0 };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+        self.beep.fill(self.sound.load(), out);
+    }
+}
+
+// Plays nothing, used when there's no sound device
+pub struct NullAudio {
+    // Only kept for the volume and mute hotkeys
+    #[cfg(feature = "sdl")]
+    tone: Tone,
+}
+
+impl NullAudio {
+    pub fn new(settings: BeepSettings) -> Self {
+        #[cfg(not(feature = "sdl"))]
+        let _ = settings;
+        NullAudio {
+            #[cfg(feature = "sdl")]
+            tone: Tone::new(settings, SAMPLE_RATE),
+        }
+    }
+}
+
+impl Audio for NullAudio {
+    fn frame(&mut self, _sound: Sound) {}
+
+    #[cfg(feature = "sdl")]
+    fn volume(&mut self) -> f32 {
+        self.tone.settings.volume
+    }
+
+    #[cfg(feature = "sdl")]
+    fn set_volume(&mut self, volume: f32) {
+        self.tone.settings.volume = volume.clamp(0.0, 1.0);
+    }
+
+    #[cfg(feature = "sdl")]
+    fn toggle_mute(&mut self) -> bool {
+        self.tone.muted = !self.tone.muted;
+        self.tone.muted
+    }
+}
+
+// Renders the beep to a 16 bit mono WAV file, one frame's worth of samples
+// at a time so the file follows emulated rather than real time
+pub struct WavAudio {
+    beep: Beep,
+    writer: Option<WavWriter<BufWriter<File>>>,
+    samples: Vec<f32>,
+}
+
+impl WavAudio {
+    pub fn create(path: &str, settings: BeepSettings) -> Result<Self, String> {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE as u32,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let writer = WavWriter::create(path, spec).map_err(|e| format!("{}: {}", path, e))?;
+
+        Ok(WavAudio {
+            beep: Beep::new(Tone::new(settings, SAMPLE_RATE)),
+            writer: Some(writer),
+            samples: vec![0.0; (SAMPLE_RATE / FPS) as usize],
+        })
+    }
+}
+
+impl Audio for WavAudio {
+    fn frame(&mut self, sound: Sound) {
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+
+        self.beep.fill(sound, &mut self.samples);
+        for sample in &self.samples {
+            if let Err(e) = writer.write_sample((sample * i16::MAX as f32) as i16) {
+                eprintln!("Failed to write audio: {}", e);
+                self.writer = None;
+                return;
+            }
+        }
+    }
+
+    #[cfg(feature = "sdl")]
+    fn volume(&mut self) -> f32 {
+        self.beep.tone.settings.volume
+    }
+
+    #[cfg(feature = "sdl")]
+    fn set_volume(&mut self, volume: f32) {
+        self.beep.tone.settings.volume = volume.clamp(0.0, 1.0);
+    }
+
+    #[cfg(feature = "sdl")]
+    fn toggle_mute(&mut self) -> bool {
+        self.beep.tone.muted = !self.beep.tone.muted;
+        self.beep.tone.muted
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(writer) => writer.finalize().map_err(io::Error::other),
+            None => Ok(()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(waveform: Waveform) -> Vec<f32> {
+        let settings = BeepSettings {
+            frequency: 1.0,
+            volume: 0.5,
+            waveform,
+        };
+        // One period, 8 samples long
+        let mut tone = Tone::new(settings, 8);
+        (0..8).map(|_| tone.next_sample()).collect()
+    }
+
+    #[test]
+    fn test_waveforms() {
+        assert_eq!(
+            samples(Waveform::Square),
+            vec![0.5, 0.5, 0.5, 0.5, -0.5, -0.5, -0.5, -0.5]
+        );
+        assert_eq!(
+            samples(Waveform::Triangle),
+            vec![-0.5, -0.25, 0.0, 0.25, 0.5, 0.25, 0.0, -0.25]
+        );
+        let sine = samples(Waveform::Sine);
+        assert!((sine[2] - 0.5).abs() < 1e-6);
+        assert!((sine[6] + 0.5).abs() < 1e-6);
+        assert!(samples(Waveform::Noise)
+            .iter()
+            .all(|s| (-0.5..=0.5).contains(s)));
+
+        assert_eq!(Waveform::parse("Sine"), Ok(Waveform::Sine));
+        assert!(Waveform::parse("sawtooth").is_err());
+    }
+
+    #[test]
+    fn test_mute() {
+        let mut tone = Tone::new(BeepSettings::default(), SAMPLE_RATE);
+        assert_ne!(tone.next_sample(), 0.0);
+        tone.muted = true;
+        assert_eq!(tone.next_sample(), 0.0);
+    }
+
+    #[test]
+    fn test_pattern() {
+        let mut bits = [0; 16];
+        bits[0] = 0b1010_0000;
+        let pattern = Pattern {
+            bits,
+            pitch: DEFAULT_PITCH,
+        };
+        assert_eq!(pattern.rate(), 4000.0);
+        assert_eq!(
+            Pattern {
+                pitch: 112,
+                ..pattern
+            }
+            .rate(),
+            8000.0
+        );
+
+        // One sample per bit
+        let settings = BeepSettings {
+            volume: 1.0,
+            ..BeepSettings::default()
+        };
+        let mut tone = Tone::new(settings, 4000);
+        tone.pattern = Some(pattern);
+        let samples: Vec<f32> = (0..130).map(|_| tone.next_sample()).collect();
+        assert_eq!(samples[..4], [1.0, -1.0, 1.0, -1.0]);
+        assert!(samples[4..128].iter().all(|&s| s == -1.0));
+        assert_eq!(samples[128..], [1.0, -1.0]);
+    }
+
+    #[cfg(feature = "sdl")]
+    #[test]
+    fn test_shared_sound() {
+        let shared = SharedSound::default();
+        let sound = Sound {
+            beeping: true,
+            pattern: Some(Pattern {
+                bits: [0xA5; 16],
+                pitch: 100,
+            }),
+        };
+        shared.store(sound);
+        assert_eq!(shared.load(), sound);
+    }
+
+    #[test]
+    fn test_ramps() {
+        let settings = BeepSettings {
+            volume: 1.0,
+            ..BeepSettings::default()
+        };
+        let mut beep = Beep::new(Tone::new(settings, SAMPLE_RATE));
+        let ramp = (RAMP_MS * SAMPLE_RATE as f32 / 1000.0) as usize;
+        let mut out = vec![1.0; ramp * 2];
+
+        let on = Sound {
+            beeping: true,
+            pattern: None,
+        };
+        beep.fill(Sound::default(), &mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+
+        // Fades in rather than jumping straight to full volume
+        beep.fill(on, &mut out);
+        assert!(out[0].abs() < 0.01);
+        assert!(out[..ramp].iter().all(|s| s.abs() < 1.0));
+        assert!(out[ramp..].iter().all(|s| s.abs() == 1.0));
+
+        beep.fill(Sound::default(), &mut out);
+        assert!(out[..ramp - 1].iter().any(|&s| s != 0.0));
+        assert!(out[ramp + 1..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_wav() {
+        let path = std::env::temp_dir().join("chip8_audio_test.wav");
+        let path = path.to_str().unwrap();
+
+        let mut audio = WavAudio::create(path, BeepSettings::default()).unwrap();
+        audio.frame(Sound::default());
+        audio.frame(Sound {
+            beeping: true,
+            pattern: None,
+        });
+        audio.finish().unwrap();
+
+        let mut reader = hound::WavReader::open(path).unwrap();
+        assert_eq!(reader.spec().sample_rate, SAMPLE_RATE as u32);
+        let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(samples.len(), 2 * 735);
+        assert!(samples[..735].iter().all(|&s| s == 0));
+        assert!(samples[735..].iter().any(|&s| s != 0));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,9 +1,86 @@
+#[cfg(feature = "sdl")]
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use std::fs;
+
+// Default keyboard key for each keypad key 0-F, named like SDL key names
+pub const KEYMAP: [&str; 16] = [
+    "X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    keys: [String; 16],
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            keys: KEYMAP.map(str::to_string),
+        }
+    }
+}
+
+impl Keymap {
+    // Keypad key bound to a keyboard key
+    pub fn keypad_key(&self, name: &str) -> Option<u8> {
+        self.keys
+            .iter()
+            .position(|key| key.eq_ignore_ascii_case(name))
+            .map(|key| key as u8)
+    }
+
+    pub fn key_name(&self, keypad: u8) -> &str {
+        &self.keys[keypad as usize]
+    }
+
+    pub fn bind(&mut self, keypad: u8, name: &str) {
+        self.keys[keypad as usize] = name.to_string();
+    }
+
+    // One binding per line, a keypad key in hex then a keyboard key name:
+    //   5 W
+    //   a = Space
+    // '#' starts a comment, keys that aren't listed keep their binding
+    pub fn apply(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (keypad, name) = line
+                .split_once(|c: char| c == '=' || c.is_whitespace())
+                .map(|(keypad, name)| (keypad.trim(), name.trim().trim_start_matches('=').trim()))
+                .filter(|(_, name)| !name.is_empty())
+                .ok_or_else(|| {
+                    format!("Line {}: expected a keypad key and a key name", number + 1)
+                })?;
+            let keypad =
+                parse_keypad_key(keypad).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+
+            self.bind(keypad, name);
+        }
+        Ok(())
+    }
+
+    pub fn apply_file(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        self.apply(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+// A keypad key written as a hex digit
+pub fn parse_keypad_key(text: &str) -> Result<u8, String> {
+    u8::from_str_radix(text, 16)
+        .ok()
+        .filter(|keypad| *keypad < 16)
+        .ok_or_else(|| format!("'{}' is not a keypad key 0-F", text))
+}
 
 pub struct InputManager {
     pub keys: u16,
     prev_keys: u16,
+    pub keymap: Keymap,
 }
 
 impl InputManager {
@@ -11,6 +88,7 @@ impl InputManager {
         InputManager {
             keys: 0,
             prev_keys: 0,
+            keymap: Keymap::default(),
         }
     }
 
@@ -22,7 +100,6 @@ impl InputManager {
     pub fn check_key_released(&self, p_key: u8) -> bool {
         let key: u16 = 1 << p_key;
         if self.prev_keys & key > 0 {
-            println!("key down");
             if self.keys & key == 0 {
                 return true;
             }
@@ -45,57 +122,58 @@ impl InputManager {
         self.keys == 0
     }
 
+    // Replaces the pressed keys, for frontends that track the keyboard themselves
+    pub fn set_keys(&mut self, keys: u16) {
+        self.prev_keys = self.keys;
+        self.keys = keys;
+    }
+
+    #[cfg(feature = "sdl")]
     pub fn handle_keyboard_input(&mut self, event: Event) {
         self.prev_keys = self.keys;
-        println!("{:016b}, {:016b}", self.keys, self.prev_keys);
         match event {
-            Event::KeyDown { keycode, .. } => {
-                let key = match keycode {
-                    Some(Keycode::X) => 1 << 0x0,
-                    Some(Keycode::Num1) => 1 << 0x1,
-                    Some(Keycode::Num2) => 1 << 0x2,
-                    Some(Keycode::Num3) => 1 << 0x3,
-                    Some(Keycode::Q) => 1 << 0x4,
-                    Some(Keycode::W) => 1 << 0x5,
-                    Some(Keycode::E) => 1 << 0x6,
-                    Some(Keycode::A) => 1 << 0x7,
-                    Some(Keycode::S) => 1 << 0x8,
-                    Some(Keycode::D) => 1 << 0x9,
-                    Some(Keycode::Z) => 1 << 0xA,
-                    Some(Keycode::C) => 1 << 0xB,
-                    Some(Keycode::Num4) => 1 << 0xC,
-                    Some(Keycode::R) => 1 << 0xD,
-                    Some(Keycode::F) => 1 << 0xE,
-                    Some(Keycode::V) => 1 << 0xF,
-                    _ => 0,
-                };
-                self.keys |= key
+            Event::KeyDown {
+                keycode: Some(keycode),
+                ..
+            } => {
+                if let Some(key) = self.keymap.keypad_key(&keycode.name()) {
+                    self.keys |= 1 << key;
+                }
             }
 
-            Event::KeyUp { keycode, .. } => {
-                let key = match keycode {
-                    Some(Keycode::X) => 0b1111_1111_1111_1110,
-                    Some(Keycode::Num1) => 0b1111_1111_1111_1101,
-                    Some(Keycode::Num2) => 0b1111_1111_1111_1011,
-                    Some(Keycode::Num3) => 0b1111_1111_1111_0111,
-                    Some(Keycode::Q) => 0b1111_1111_1110_1111,
-                    Some(Keycode::W) => 0b1111_1111_1101_1111,
-                    Some(Keycode::E) => 0b1111_1111_1011_1111,
-                    Some(Keycode::A) => 0b1111_1111_0111_1111,
-                    Some(Keycode::S) => 0b1111_1110_1111_1111,
-                    Some(Keycode::D) => 0b1111_1101_1111_1111,
-                    Some(Keycode::Z) => 0b1111_1011_1111_1111,
-                    Some(Keycode::C) => 0b1111_0111_1111_1111,
-                    Some(Keycode::Num4) => 0b1110_1111_1111_1111,
-                    Some(Keycode::R) => 0b1101_1111_1111_1111,
-                    Some(Keycode::F) => 0b1011_1111_1111_1111,
-                    Some(Keycode::V) => 0b0111_1111_1111_1111,
-                    _ => u16::MAX,
-                };
-                self.keys &= key;
+            Event::KeyUp {
+                keycode: Some(keycode),
+                ..
+            } => {
+                if let Some(key) = self.keymap.keypad_key(&keycode.name()) {
+                    self.keys &= !(1 << key);
+                }
             }
 
             _ => (),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keymap() {
+        let mut keymap = Keymap::default();
+        keymap
+            .apply("# arrows\n5 Up\n8 = Down\n\nA Space # fire\n")
+            .unwrap();
+        assert_eq!(keymap.keypad_key("up"), Some(0x5));
+        assert_eq!(keymap.keypad_key("Down"), Some(0x8));
+        assert_eq!(keymap.keypad_key("Space"), Some(0xA));
+        // Unlisted keys keep their default, replaced ones are unbound
+        assert_eq!(keymap.keypad_key("X"), Some(0x0));
+        assert_eq!(keymap.keypad_key("W"), None);
+
+        assert!(keymap.apply("G Up").is_err());
+        assert!(keymap.apply("10 Up").is_err());
+        assert!(keymap.apply("5").is_err());
+    }
+}
@@ -1,4 +1,5 @@
 pub mod audio_driver;
 pub mod input_driver;
 pub mod rom_driver;
+pub mod terminal_driver;
 pub mod video_driver;
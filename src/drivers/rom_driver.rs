@@ -1,34 +1,240 @@
+use std::fmt;
 use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::config::Platform;
+use crate::database::{self, Database, Metadata};
 
 pub enum ProgramType {
-    Test(u8),
+    // Name or number of one of the TESTS
+    Test(String),
     Path(String),
 }
 
+// Timendus' CHIP-8 test suite, built into the binary so the tests can be run
+// from any directory
+pub const TESTS: [(&str, &[u8]); 8] = [
+    (
+        "1-chip8-logo",
+        include_bytes!("../../roms/tests/1-chip8-logo.ch8"),
+    ),
+    (
+        "2-ibm-logo",
+        include_bytes!("../../roms/tests/2-ibm-logo.ch8"),
+    ),
+    ("3-corax+", include_bytes!("../../roms/tests/3-corax+.ch8")),
+    ("4-flags", include_bytes!("../../roms/tests/4-flags.ch8")),
+    ("5-quirks", include_bytes!("../../roms/tests/5-quirks.ch8")),
+    ("6-keypad", include_bytes!("../../roms/tests/6-keypad.ch8")),
+    ("7-beep", include_bytes!("../../roms/tests/7-beep.ch8")),
+    (
+        "8-scrolling",
+        include_bytes!("../../roms/tests/8-scrolling.ch8"),
+    ),
+];
+
+// Matches "3", "corax+" or "3-corax+", ignoring case
+pub fn find_test(name: &str) -> Option<(&'static str, &'static [u8])> {
+    let name = name.trim().to_lowercase();
+    TESTS.iter().copied().find(|(test, _)| {
+        let (number, title) = test.split_once('-').unwrap_or((test, test));
+        name == *test || name == number || name == title
+    })
+}
+
 pub struct Program {
     pub bytes: Vec<u8>,
+    // File name without the extension
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RomError {
+    NotFound(String),
+    Unreadable {
+        path: String,
+        error: String,
+    },
+    UnknownTest(String),
+    Empty(String),
+    TooLarge {
+        name: String,
+        size: usize,
+        available: usize,
+        platform: Platform,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::NotFound(path) => write!(f, "ROM not found: {}", path),
+            RomError::Unreadable { path, error } => {
+                write!(f, "Unable to read ROM {}: {}", path, error)
+            }
+            RomError::UnknownTest(test) => {
+                write!(f, "Unknown test program '{}', see --list-tests", test)
+            }
+            RomError::Empty(name) => write!(f, "{} is empty", name),
+            RomError::TooLarge {
+                name,
+                size,
+                available,
+                platform,
+            } => write!(
+                f,
+                "{} is {} bytes, more than the {} bytes of memory {} programs can use",
+                name,
+                size,
+                available,
+                platform.name()
+            ),
+        }
+    }
+}
+
+// Problems that still let the ROM run
+#[derive(Debug, PartialEq)]
+pub enum RomWarning {
+    // Instructions are 2 bytes, so the last byte is probably truncated or padding
+    OddLength(usize),
+}
+
+impl fmt::Display for RomWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomWarning::OddLength(size) => write!(
+                f,
+                "ROM is {} bytes, an odd length, it may be truncated",
+                size
+            ),
+        }
+    }
 }
 
 impl Program {
-    pub fn new(path: ProgramType) -> Self {
+    pub fn load(path: ProgramType) -> Result<Self, RomError> {
         let program_path = match path {
-            ProgramType::Test(test_num) => match test_num {
-                1 => "roms/tests/1-chip8-logo.ch8",
-                2 => "roms/tests/2-ibm-logo.ch8",
-                3 => "roms/tests/3-corax+.ch8",
-                4 => "roms/tests/4-flags.ch8",
-                5 => "roms/tests/5-quirks.ch8",
-                6 => "roms/tests/6-keypad.ch8",
-                7 => "roms/tests/7-beep.ch8",
-                8 => "roms/tests/8-scrolling.ch8",
-                _ => panic!("The test program must be from 1-8"),
+            ProgramType::Test(test) => {
+                let (name, bytes) = find_test(&test).ok_or(RomError::UnknownTest(test))?;
+                return Ok(Program {
+                    bytes: bytes.to_vec(),
+                    name: name.to_string(),
+                });
             }
-            .to_string(),
             ProgramType::Path(p) => p,
         };
 
-        let bytes = fs::read(program_path).expect("Unable to read file");
+        let name = Path::new(&program_path)
+            .file_stem()
+            .map_or("rom".to_string(), |s| s.to_string_lossy().to_string());
+        let bytes = fs::read(&program_path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => RomError::NotFound(program_path.clone()),
+            _ => RomError::Unreadable {
+                path: program_path.clone(),
+                error: e.to_string(),
+            },
+        })?;
+        if bytes.is_empty() {
+            return Err(RomError::Empty(program_path));
+        }
+
+        Ok(Program { bytes, name })
+    }
+
+    pub fn sha1(&self) -> String {
+        database::sha1(&self.bytes)
+    }
+
+    pub fn metadata(&self, database: &Database) -> Option<Metadata> {
+        database.lookup(&self.sha1())
+    }
+
+    // Checks the program fits in the platform's memory
+    pub fn check(&self, platform: Platform) -> Result<Vec<RomWarning>, RomError> {
+        let available = platform.program_space();
+        if self.bytes.len() > available {
+            return Err(RomError::TooLarge {
+                name: self.name.clone(),
+                size: self.bytes.len(),
+                available,
+                platform,
+            });
+        }
+
+        let mut warnings = Vec::new();
+        if self.bytes.len() % 2 == 1 {
+            warnings.push(RomWarning::OddLength(self.bytes.len()));
+        }
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+
+    fn load(name: &str, bytes: &[u8]) -> Result<Program, RomError> {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, bytes).unwrap();
+        let program = Program::load(ProgramType::Path(path.to_string_lossy().into_owned()));
+        fs::remove_file(path).unwrap();
+        program
+    }
 
-        Program { bytes }
+    #[test]
+    fn test_load_errors() {
+        assert_eq!(
+            Program::load(ProgramType::Path("no/such/rom.ch8".to_string())).err(),
+            Some(RomError::NotFound("no/such/rom.ch8".to_string()))
+        );
+        assert_eq!(
+            Program::load(ProgramType::Test("9".to_string())).err(),
+            Some(RomError::UnknownTest("9".to_string()))
+        );
+        assert!(matches!(
+            load("rom_driver_test_empty.ch8", &[]),
+            Err(RomError::Empty(_))
+        ));
+
+        let program = load("rom_driver_test_ok.ch8", &[0x12, 0x00]).unwrap();
+        assert_eq!(program.name, "rom_driver_test_ok");
+        assert_eq!(program.check(Platform::Chip8), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_find_test() {
+        for name in ["3", "corax+", "3-corax+", "CORAX+"] {
+            assert_eq!(find_test(name).unwrap().0, "3-corax+");
+        }
+        assert!(find_test("0").is_none());
+        assert!(find_test("corax").is_none());
+
+        let program = Program::load(ProgramType::Test("ibm-logo".to_string())).unwrap();
+        assert_eq!(program.name, "2-ibm-logo");
+        assert_eq!(program.bytes[..2], [0x00, 0xE0]);
+    }
+
+    #[test]
+    fn test_check() {
+        let program = Program {
+            bytes: vec![0; MEM_SIZE - PROGRAM_START + 1],
+            name: "big".to_string(),
+        };
+        assert_eq!(
+            program.check(Platform::XoChip).unwrap_err().to_string(),
+            "big is 3585 bytes, more than the 3584 bytes of memory xochip programs can use"
+        );
+
+        let program = Program {
+            bytes: vec![0x00, 0xE0, 0x12],
+            name: "odd".to_string(),
+        };
+        assert_eq!(
+            program.check(Platform::Chip8),
+            Ok(vec![RomWarning::OddLength(3)])
+        );
     }
 }
@@ -0,0 +1,292 @@
+/*
+Terminal frontend.
+
+The screen is drawn with Unicode characters in 24 bit colour: half blocks give
+two pixels per character, one above the other, and braille gives a 2x4 block of
+pixels per character in a single colour. Keys use the same bindings as the SDL
+window. Most terminals never report key releases, so there a key counts as held
+for a short time after each press or repeat.
+*/
+
+use std::io::{self, Stdout, Write};
+use std::time::{Duration, Instant};
+
+use crossterm::event::{
+    self, Event, KeyCode, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::{cursor, execute, queue, terminal};
+
+use super::input_driver::Keymap;
+use crate::framebuffer::Framebuffer;
+use crate::palette::Palette;
+
+// Long enough to bridge the gap before the terminal starts repeating a key
+const KEY_HOLD: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Glyphs {
+    HalfBlock,
+    Braille,
+}
+
+fn fg(colour: [u8; 3]) -> String {
+    format!("\x1b[38;2;{};{};{}m", colour[0], colour[1], colour[2])
+}
+
+fn bg(colour: [u8; 3]) -> String {
+    format!("\x1b[48;2;{};{};{}m", colour[0], colour[1], colour[2])
+}
+
+// One string per terminal row, two pixel rows each
+pub fn render_half_blocks(framebuffer: &Framebuffer, palette: &Palette) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for y in (0..framebuffer.height()).step_by(2) {
+        let mut line = String::new();
+        let mut last = None;
+        for x in 0..framebuffer.width() {
+            let top = palette[framebuffer.pixel(x, y) as usize];
+            let bottom = palette[framebuffer.pixel(x, y + 1) as usize];
+            // Only change colours when they differ from the previous character
+            if last != Some((top, bottom)) {
+                line.push_str(&fg(top));
+                line.push_str(&bg(bottom));
+                last = Some((top, bottom));
+            }
+            line.push('▀');
+        }
+        line.push_str("\x1b[0m");
+        lines.push(line);
+    }
+
+    lines
+}
+
+// One string per terminal row, four pixel rows each
+pub fn render_braille(framebuffer: &Framebuffer, palette: &Palette) -> Vec<String> {
+    // Dot bits for each pixel of a 2x4 cell, by row then column
+    const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+    let mut lines = Vec::new();
+
+    for cell_y in (0..framebuffer.height()).step_by(4) {
+        let mut line = bg(palette[0]);
+        let mut last = None;
+        for cell_x in (0..framebuffer.width()).step_by(2) {
+            let mut dots = 0;
+            // The cell is drawn in the colour of its most common lit pixel
+            let mut counts = [0; 4];
+            for (dy, row) in DOTS.iter().enumerate() {
+                for (dx, dot) in row.iter().enumerate() {
+                    let pixel = framebuffer.pixel(cell_x + dx, cell_y + dy);
+                    if pixel != 0 {
+                        dots |= dot;
+                        counts[pixel as usize] += 1;
+                    }
+                }
+            }
+
+            let index = (1..4).max_by_key(|i| counts[*i]).unwrap();
+            if dots != 0 && last != Some(index) {
+                line.push_str(&fg(palette[index]));
+                last = Some(index);
+            }
+            line.push(char::from_u32(0x2800 + dots).unwrap());
+        }
+        line.push_str("\x1b[0m");
+        lines.push(line);
+    }
+
+    lines
+}
+
+#[derive(Default)]
+pub struct TerminalInput {
+    pub keys: u16,
+    pub quit: bool,
+}
+
+pub struct TerminalFrontend {
+    stdout: Stdout,
+    glyphs: Glyphs,
+    keymap: Keymap,
+    // Whether the terminal reports key releases
+    releases: bool,
+    // When each keypad key was last pressed, or None if it is up
+    pressed: [Option<Instant>; 16],
+}
+
+impl TerminalFrontend {
+    // Switches the terminal to raw mode on an alternate screen until dropped
+    pub fn new(glyphs: Glyphs, keymap: Keymap) -> io::Result<Self> {
+        let mut stdout = io::stdout();
+        terminal::enable_raw_mode()?;
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+        let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if releases {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+
+        Ok(TerminalFrontend {
+            stdout,
+            glyphs,
+            keymap,
+            releases,
+            pressed: [None; 16],
+        })
+    }
+
+    pub fn present(
+        &mut self,
+        framebuffer: &Framebuffer,
+        palette: &Palette,
+        status: &str,
+    ) -> io::Result<()> {
+        let lines = match self.glyphs {
+            Glyphs::HalfBlock => render_half_blocks(framebuffer, palette),
+            Glyphs::Braille => render_braille(framebuffer, palette),
+        };
+
+        queue!(self.stdout, cursor::MoveTo(0, 0))?;
+        for line in lines {
+            write!(self.stdout, "{}\r\n", line)?;
+        }
+        queue!(
+            self.stdout,
+            terminal::Clear(terminal::ClearType::UntilNewLine)
+        )?;
+        write!(self.stdout, "{}", status)?;
+        queue!(
+            self.stdout,
+            terminal::Clear(terminal::ClearType::UntilNewLine)
+        )?;
+        self.stdout.flush()
+    }
+
+    pub fn beep(&mut self) -> io::Result<()> {
+        write!(self.stdout, "\x07")?;
+        self.stdout.flush()
+    }
+
+    // Reads all waiting key events. Esc or Ctrl+C quits
+    pub fn poll_input(&mut self) -> io::Result<TerminalInput> {
+        let mut input = TerminalInput::default();
+        let now = Instant::now();
+
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.code == KeyCode::Esc || ctrl_c {
+                input.quit = true;
+                continue;
+            }
+
+            let Some(name) = key_name(key.code) else {
+                continue;
+            };
+            if let Some(keypad) = self.keymap.keypad_key(&name) {
+                self.pressed[keypad as usize] = match key.kind {
+                    KeyEventKind::Release => None,
+                    _ => Some(now),
+                };
+            }
+        }
+
+        for (keypad, pressed) in self.pressed.iter_mut().enumerate() {
+            if !self.releases && pressed.is_some_and(|at| now - at > KEY_HOLD) {
+                *pressed = None;
+            }
+            if pressed.is_some() {
+                input.keys |= 1 << keypad;
+            }
+        }
+
+        Ok(input)
+    }
+}
+
+// The SDL name of a key, which is what the keymap binds
+fn key_name(code: KeyCode) -> Option<String> {
+    let name = match code {
+        KeyCode::Char(' ') => "Space",
+        KeyCode::Char(c) => return Some(c.to_string()),
+        KeyCode::F(n) => return Some(format!("F{}", n)),
+        KeyCode::Up => "Up",
+        KeyCode::Down => "Down",
+        KeyCode::Left => "Left",
+        KeyCode::Right => "Right",
+        KeyCode::Enter => "Return",
+        KeyCode::Tab => "Tab",
+        KeyCode::Backspace => "Backspace",
+        KeyCode::Delete => "Delete",
+        KeyCode::Insert => "Insert",
+        KeyCode::Home => "Home",
+        KeyCode::End => "End",
+        KeyCode::PageUp => "PageUp",
+        KeyCode::PageDown => "PageDown",
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+impl Drop for TerminalFrontend {
+    fn drop(&mut self) {
+        if self.releases {
+            let _ = execute!(self.stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::DEFAULT_PALETTE;
+
+    #[test]
+    fn test_render() {
+        let mut framebuffer = Framebuffer::new();
+        // Top left pixel, and a 2x4 block at x = 2
+        framebuffer.draw_sprite(0, 0, 0, &[0x80]);
+        framebuffer.draw_sprite(0, 2, 0, &[0xC0, 0xC0, 0xC0, 0xC0]);
+
+        let lines = render_half_blocks(&framebuffer, &DEFAULT_PALETTE);
+        assert_eq!(lines.len(), 16);
+        assert_eq!(lines[0].chars().filter(|c| *c == '▀').count(), 64);
+        assert!(lines[0].starts_with("\x1b[38;2;255;255;255m\x1b[48;2;0;0;0m▀"));
+
+        let lines = render_braille(&framebuffer, &DEFAULT_PALETTE);
+        assert_eq!(lines.len(), 8);
+        let cells: Vec<char> = lines[0]
+            .chars()
+            .filter(|c| ('\u{2800}'..='\u{28FF}').contains(c))
+            .collect();
+        assert_eq!(cells.len(), 32);
+        assert_eq!(cells[0], '\u{2801}');
+        assert_eq!(cells[1], '\u{28FF}');
+        assert_eq!(cells[2], '\u{2800}');
+    }
+
+    #[test]
+    fn test_key_names() {
+        let mut keymap = Keymap::default();
+        keymap.bind(5, "Up");
+        keymap.bind(6, "Space");
+        keymap.bind(0xA, "Return");
+
+        let keypad = |code| key_name(code).and_then(|name| keymap.keypad_key(&name));
+        assert_eq!(keypad(KeyCode::Up), Some(5));
+        assert_eq!(keypad(KeyCode::Char(' ')), Some(6));
+        assert_eq!(keypad(KeyCode::Enter), Some(0xA));
+        assert_eq!(keypad(KeyCode::Char('Q')), Some(4));
+        assert_eq!(key_name(KeyCode::Esc), None);
+    }
+}
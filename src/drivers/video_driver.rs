@@ -1 +1,252 @@
+#[cfg(feature = "sdl")]
+use sdl2::{
+    pixels::{Color, PixelFormatEnum},
+    rect::Rect,
+    render::{Canvas, Texture},
+    video::{FullscreenType, Window},
+    VideoSubsystem,
+};
 
+#[cfg(feature = "sdl")]
+use crate::filters::{self, Filter};
+use crate::framebuffer::Framebuffer;
+#[cfg(feature = "sdl")]
+use crate::osd::{self, OverlayText};
+use crate::palette::Palette;
+
+// Converts the framebuffer to an RGB24 frame
+pub fn render(framebuffer: &Framebuffer, palette: &Palette) -> Vec<u8> {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let mut frame = Vec::with_capacity(width * height * 3);
+
+    for y in 0..height {
+        for x in 0..width {
+            frame.extend_from_slice(&palette[framebuffer.pixel(x, y) as usize]);
+        }
+    }
+
+    frame
+}
+
+// Something frames can be presented on. Frames are RGB24, row by row
+pub trait Display {
+    fn present(&mut self, frame: &[u8], width: u32, height: u32);
+
+    fn present_framebuffer(&mut self, framebuffer: &Framebuffer, palette: &Palette) {
+        let frame = render(framebuffer, palette);
+        self.present(
+            &frame,
+            framebuffer.width() as u32,
+            framebuffer.height() as u32,
+        );
+    }
+
+    // SDL window the display is drawn in, if any
+    #[cfg(feature = "sdl")]
+    fn window_id(&self) -> Option<u32> {
+        None
+    }
+}
+
+// Where a frame is drawn in the window: centred, keeping its aspect ratio, and
+// with integer scaling only scaled by whole multiples
+#[cfg(feature = "sdl")]
+pub fn letterbox(window: (u32, u32), frame: (u32, u32), integer_scaling: bool) -> Rect {
+    let scale_x = window.0 as f32 / frame.0 as f32;
+    let scale_y = window.1 as f32 / frame.1 as f32;
+    let mut scale = scale_x.min(scale_y);
+    if integer_scaling && scale >= 1.0 {
+        scale = scale.floor();
+    }
+
+    let width = (frame.0 as f32 * scale) as u32;
+    let height = (frame.1 as f32 * scale) as u32;
+    let x = (window.0 - width) / 2;
+    let y = (window.1 - height) / 2;
+    Rect::new(x as i32, y as i32, width, height)
+}
+
+#[cfg(feature = "sdl")]
+pub struct SdlDisplay {
+    canvas: Canvas<Window>,
+    texture: Option<Texture>,
+    texture_size: (u32, u32),
+    integer_scaling: bool,
+    filters: Vec<Filter>,
+    overlay: Vec<OverlayText>,
+}
+
+#[cfg(feature = "sdl")]
+impl SdlDisplay {
+    pub fn new(
+        video_subsystem: &VideoSubsystem,
+        title: &str,
+        width: u32,
+        height: u32,
+        scale: u32,
+    ) -> Result<Self, String> {
+        let window = video_subsystem
+            .window(title, width * scale, height * scale)
+            .position_centered()
+            .resizable()
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+
+        Ok(SdlDisplay {
+            canvas,
+            texture: None,
+            texture_size: (0, 0),
+            integer_scaling: false,
+            filters: Vec::new(),
+            overlay: Vec::new(),
+        })
+    }
+
+    pub fn hide(&mut self) {
+        self.canvas.window_mut().hide();
+    }
+
+    pub fn set_integer_scaling(&mut self, integer_scaling: bool) {
+        self.integer_scaling = integer_scaling;
+    }
+
+    // Filters run over every frame before it is uploaded
+    pub fn set_filters(&mut self, filters: Vec<Filter>) {
+        self.filters = filters;
+    }
+
+    // Text drawn over following frames, after the filters
+    pub fn set_overlay(&mut self, overlay: Vec<OverlayText>) {
+        self.overlay = overlay;
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let fullscreen = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        if let Err(e) = window.set_fullscreen(fullscreen) {
+            eprintln!("Unable to toggle fullscreen: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl Display for SdlDisplay {
+    fn present(&mut self, frame: &[u8], width: u32, height: u32) {
+        let mut output = (frame.to_vec(), width as usize, height as usize);
+        if !self.filters.is_empty() {
+            output = filters::apply(&self.filters, &output.0, output.1, output.2);
+        }
+        if !self.overlay.is_empty() {
+            output = osd::draw_overlay(&output.0, output.1, output.2, &self.overlay);
+        }
+        let (frame, width, height) = (output.0.as_slice(), output.1 as u32, output.2 as u32);
+
+        // The texture only needs to be recreated when the resolution changes
+        if self.texture.is_none() || self.texture_size != (width, height) {
+            if let Some(texture) = self.texture.take() {
+                // Safe as the texture is never used again
+                unsafe { texture.destroy() };
+            }
+            self.texture = self
+                .canvas
+                .create_texture_streaming(PixelFormatEnum::RGB24, width, height)
+                .ok();
+            self.texture_size = (width, height);
+        }
+
+        if let Some(texture) = self.texture.as_mut() {
+            texture.update(None, frame, width as usize * 3).unwrap();
+
+            let output = self.canvas.output_size().unwrap();
+            let target = letterbox(output, (width, height), self.integer_scaling);
+
+            self.canvas.set_draw_color(Color::BLACK);
+            self.canvas.clear();
+            self.canvas.copy(texture, None, target).unwrap();
+            self.canvas.present();
+        }
+    }
+
+    fn window_id(&self) -> Option<u32> {
+        Some(self.canvas.window().id())
+    }
+}
+
+// Keeps the last frame in memory, for running without a window
+#[derive(Default)]
+pub struct HeadlessDisplay {
+    pub frame: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Display for HeadlessDisplay {
+    fn present(&mut self, frame: &[u8], width: u32, height: u32) {
+        self.frame.clear();
+        self.frame.extend_from_slice(frame);
+        self.width = width;
+        self.height = height;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::cpu::CPU;
+    use crate::palette::DEFAULT_PALETTE;
+
+    #[cfg(feature = "sdl")]
+    #[test]
+    fn test_letterbox() {
+        // Exact fit
+        assert_eq!(
+            letterbox((1024, 512), (64, 32), false),
+            Rect::new(0, 0, 1024, 512)
+        );
+        // Hi-res fills the same window
+        assert_eq!(
+            letterbox((1024, 512), (128, 64), false),
+            Rect::new(0, 0, 1024, 512)
+        );
+        // Bars above and below a wide frame
+        assert_eq!(
+            letterbox((1000, 1000), (64, 32), false),
+            Rect::new(0, 250, 1000, 500)
+        );
+        // Bars either side of a square frame
+        assert_eq!(
+            letterbox((1024, 512), (64, 64), false),
+            Rect::new(256, 0, 512, 512)
+        );
+        // Integer scaling rounds down to a whole multiple
+        assert_eq!(
+            letterbox((1000, 1000), (64, 32), true),
+            Rect::new(20, 260, 960, 480)
+        );
+    }
+
+    #[test]
+    fn test_headless_present() {
+        let mut cpu = CPU::new(Config::default());
+        // I = font "0", draw at V0 V0
+        cpu.load_program(vec![0xA0, 0x50, 0xD0, 0x05]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        let mut display = HeadlessDisplay::default();
+        display.present_framebuffer(&cpu.framebuffer, &DEFAULT_PALETTE);
+
+        assert_eq!((display.width, display.height), (64, 32));
+        assert_eq!(display.frame.len(), 64 * 32 * 3);
+
+        // Top row of the "0" glyph is 0xF0
+        let row: Vec<u8> = display.frame[..8 * 3].iter().step_by(3).copied().collect();
+        assert_eq!(row, vec![0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]);
+    }
+}
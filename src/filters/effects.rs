@@ -0,0 +1,167 @@
+/*
+CRT and LCD effects.
+
+These work in place on an RGB24 image that has already been scaled up, where
+`cell` is the size in output pixels of one emulated pixel.
+*/
+
+// Brightness kept by the darkened rows and columns
+const SCANLINE_LEVEL: f32 = 0.5;
+const GRID_LEVEL: f32 = 0.6;
+// Brightness above which pixels start to glow, and how strongly
+const BLOOM_THRESHOLD: f32 = 0.6;
+const BLOOM_STRENGTH: f32 = 0.6;
+// Amount of barrel distortion at the corners
+const CURVATURE: f32 = 0.15;
+
+fn darken(pixel: &mut [u8], level: f32) {
+    for channel in pixel {
+        *channel = (*channel as f32 * level) as u8;
+    }
+}
+
+// Darkens the bottom quarter of every emulated row
+pub fn scanlines(rgb: &mut [u8], width: usize, cell: usize) {
+    let dark_rows = (cell / 4).max(1);
+    for (y, row) in rgb.chunks_mut(width * 3).enumerate() {
+        if y % cell >= cell - dark_rows {
+            darken(row, SCANLINE_LEVEL);
+        }
+    }
+}
+
+// Gaps between pixels, like an LCD
+pub fn grid(rgb: &mut [u8], width: usize, cell: usize) {
+    for (y, row) in rgb.chunks_mut(width * 3).enumerate() {
+        for (x, pixel) in row.chunks_mut(3).enumerate() {
+            if x % cell == cell - 1 || y % cell == cell - 1 {
+                darken(pixel, GRID_LEVEL);
+            }
+        }
+    }
+}
+
+// Bright pixels glow onto their neighbours, blurred over about a cell
+pub fn bloom(rgb: &mut [u8], width: usize, height: usize, cell: usize) {
+    let glow: Vec<f32> = rgb
+        .chunks(3)
+        .flat_map(|pixel| {
+            let luma =
+                (0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32)
+                    / 255.0;
+            let amount = ((luma - BLOOM_THRESHOLD) / (1.0 - BLOOM_THRESHOLD)).max(0.0);
+            pixel
+                .iter()
+                .map(move |c| *c as f32 * amount)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let radius = cell.max(1);
+    let glow = box_blur(&glow, width, height, radius, true);
+    let glow = box_blur(&glow, width, height, radius, false);
+
+    for (channel, glow) in rgb.iter_mut().zip(glow) {
+        *channel = (*channel as f32 + glow * BLOOM_STRENGTH).min(255.0) as u8;
+    }
+}
+
+// Averages each channel over 2 * radius + 1 pixels along rows or columns
+fn box_blur(data: &[f32], width: usize, height: usize, radius: usize, rows: bool) -> Vec<f32> {
+    let mut out = vec![0.0; data.len()];
+    let (lines, length) = if rows {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let index = |line: usize, pos: usize| {
+        if rows {
+            (line * width + pos) * 3
+        } else {
+            (pos * width + line) * 3
+        }
+    };
+    let span = (radius * 2 + 1) as f32;
+
+    for line in 0..lines {
+        for channel in 0..3 {
+            let mut sum = 0.0;
+            for pos in 0..length.min(radius + 1) {
+                sum += data[index(line, pos) + channel];
+            }
+            for pos in 0..length {
+                out[index(line, pos) + channel] = sum / span;
+                if pos + radius + 1 < length {
+                    sum += data[index(line, pos + radius + 1) + channel];
+                }
+                if pos >= radius {
+                    sum -= data[index(line, pos - radius) + channel];
+                }
+            }
+        }
+    }
+
+    out
+}
+
+// Bends the image like the face of a CRT, corners outside the tube are black
+pub fn curvature(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut out = vec![0; rgb.len()];
+    // Shrink so the middle of each edge stays on screen
+    let fit = 1.0 / (1.0 + CURVATURE);
+
+    for y in 0..height {
+        for x in 0..width {
+            let u = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+            let v = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+            let bend = (1.0 + CURVATURE * (u * u + v * v)) * fit;
+            let (u, v) = (u * bend, v * bend);
+            if u.abs() > 1.0 || v.abs() > 1.0 {
+                continue;
+            }
+
+            let src_x = (((u + 1.0) / 2.0 * width as f32) as usize).min(width - 1);
+            let src_y = (((v + 1.0) / 2.0 * height as f32) as usize).min(height - 1);
+            let src = (src_y * width + src_x) * 3;
+            let dst = (y * width + x) * 3;
+            out[dst..dst + 3].copy_from_slice(&rgb[src..src + 3]);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scanlines_and_grid() {
+        // 4x4 white image, one emulated pixel
+        let mut rgb = vec![0xFF; 4 * 4 * 3];
+        scanlines(&mut rgb, 4, 4);
+        assert_eq!(rgb[0], 0xFF);
+        assert_eq!(rgb[3 * 4 * 3], 0x7F);
+
+        let mut rgb = vec![0xFF; 4 * 4 * 3];
+        grid(&mut rgb, 4, 4);
+        assert_eq!(rgb[0], 0xFF);
+        assert_eq!(rgb[3 * 3], 0x99);
+        assert_eq!(rgb[3 * 4 * 3], 0x99);
+    }
+
+    #[test]
+    fn test_bloom_and_curvature() {
+        // A single white pixel glows onto its black neighbours
+        let mut rgb = vec![0; 5 * 5 * 3];
+        rgb[(2 * 5 + 2) * 3..(2 * 5 + 2) * 3 + 3].fill(0xFF);
+        bloom(&mut rgb, 5, 5, 1);
+        assert!(rgb[(2 * 5 + 1) * 3] > 0);
+        assert_eq!(rgb[0], 0);
+
+        let rgb = vec![0xFF; 16 * 16 * 3];
+        let curved = curvature(&rgb, 16, 16);
+        assert_eq!(curved[(8 * 16 + 8) * 3], 0xFF);
+        assert_eq!(curved[0], 0);
+    }
+}
@@ -0,0 +1,131 @@
+/*
+Display filters applied in software to RGB24 frames.
+
+A chain of filters is run in order. Effects need room to draw between emulated
+pixels, so before the first one the frame is enlarged to at least EFFECT_SCALE
+times the emulated resolution.
+*/
+
+#[cfg(feature = "sdl")]
+pub mod effects;
+pub mod persistence;
+#[cfg(feature = "sdl")]
+pub mod scalers;
+
+#[cfg(feature = "sdl")]
+use crate::image;
+
+#[cfg(feature = "sdl")]
+const EFFECT_SCALE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Scale2x,
+    Scale3x,
+    Scanlines,
+    Grid,
+    Bloom,
+    Curvature,
+}
+
+impl Filter {
+    pub const NAMES: [&'static str; 6] = ["scale2x", "scale3x", "scanlines", "lcd", "bloom", "crt"];
+
+    #[cfg(feature = "sdl")]
+    fn is_effect(&self) -> bool {
+        !matches!(self, Filter::Scale2x | Filter::Scale3x)
+    }
+}
+
+// Parses a comma separated list of filter names, e.g. "scale2x,scanlines"
+pub fn parse_filters(names: &str) -> Result<Vec<Filter>, String> {
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| match name.to_lowercase().as_str() {
+            "scale2x" => Ok(Filter::Scale2x),
+            "scale3x" => Ok(Filter::Scale3x),
+            "scanlines" => Ok(Filter::Scanlines),
+            "lcd" | "grid" => Ok(Filter::Grid),
+            "bloom" => Ok(Filter::Bloom),
+            "crt" | "curvature" => Ok(Filter::Curvature),
+            _ => Err(format!(
+                "Unknown filter '{}', expected one of: {}",
+                name,
+                Filter::NAMES.join(", ")
+            )),
+        })
+        .collect()
+}
+
+#[cfg(feature = "sdl")]
+// Runs the filters over a frame, returning the new frame and its size
+pub fn apply(
+    filters: &[Filter],
+    frame: &[u8],
+    width: usize,
+    height: usize,
+) -> (Vec<u8>, usize, usize) {
+    let mut frame = frame.to_vec();
+    let (mut out_width, mut out_height) = (width, height);
+
+    for filter in filters {
+        // Emulated pixels are cell x cell output pixels
+        let mut cell = out_width / width;
+        if filter.is_effect() && cell < EFFECT_SCALE {
+            let factor = EFFECT_SCALE.div_ceil(cell);
+            frame = image::scale(&frame, out_width, out_height, factor);
+            out_width *= factor;
+            out_height *= factor;
+            cell *= factor;
+        }
+
+        match filter {
+            Filter::Scale2x => {
+                frame = scalers::scale2x(&frame, out_width, out_height);
+                out_width *= 2;
+                out_height *= 2;
+            }
+            Filter::Scale3x => {
+                frame = scalers::scale3x(&frame, out_width, out_height);
+                out_width *= 3;
+                out_height *= 3;
+            }
+            Filter::Scanlines => effects::scanlines(&mut frame, out_width, cell),
+            Filter::Grid => effects::grid(&mut frame, out_width, cell),
+            Filter::Bloom => effects::bloom(&mut frame, out_width, out_height, cell),
+            Filter::Curvature => frame = effects::curvature(&frame, out_width, out_height),
+        }
+    }
+
+    (frame, out_width, out_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let filters = parse_filters("scale2x, scanlines").unwrap();
+        assert_eq!(filters, vec![Filter::Scale2x, Filter::Scanlines]);
+        assert!(parse_filters("sepia").is_err());
+        assert_eq!(parse_filters(""), Ok(vec![]));
+    }
+
+    #[cfg(feature = "sdl")]
+    #[test]
+    fn test_apply() {
+        let filters = [Filter::Scale2x, Filter::Scanlines];
+        let frame = vec![0xFF; 64 * 32 * 3];
+        let (out, width, height) = apply(&filters, &frame, 64, 32);
+        // Scale2x, then doubled again to make room for the scanlines
+        assert_eq!((width, height), (256, 128));
+        assert_eq!(out.len(), 256 * 128 * 3);
+        assert_eq!(out[3 * 256 * 3], 0x7F);
+
+        let (_, width, height) = apply(&[], &frame, 64, 32);
+        assert_eq!((width, height), (64, 32));
+    }
+}
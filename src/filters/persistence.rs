@@ -0,0 +1,169 @@
+/*
+Flicker reduction.
+
+CHIP-8 games move sprites by erasing and redrawing them, so a pixel can be off
+for a frame or two while it is really meant to be lit. Each plane of each
+pixel gets a brightness between 0 and 1 that either averages the last few
+frames or decays like a phosphor, and colours are mixed from the palette by
+those brightnesses. Works on the framebuffer, so it needs no window.
+*/
+
+use std::collections::VecDeque;
+
+use crate::framebuffer::{Framebuffer, PLANES};
+use crate::palette::Palette;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Persistence {
+    // Average of the last n frames
+    Blend(usize),
+    // Fraction of a pixel's brightness kept each frame after it turns off
+    Decay(f32),
+}
+
+pub struct PersistenceFilter {
+    persistence: Persistence,
+    width: usize,
+    height: usize,
+    levels: Vec<[f32; PLANES]>,
+    // Colour indices of recent frames, newest last, only used for blending
+    history: VecDeque<Vec<u8>>,
+}
+
+impl PersistenceFilter {
+    pub fn new(persistence: Persistence) -> Self {
+        PersistenceFilter {
+            persistence,
+            width: 0,
+            height: 0,
+            levels: Vec::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Should be called once per frame
+    pub fn update(&mut self, framebuffer: &Framebuffer) {
+        let (width, height) = (framebuffer.width(), framebuffer.height());
+
+        // Nothing carries over a resolution change
+        if (width, height) != (self.width, self.height) {
+            self.width = width;
+            self.height = height;
+            self.levels = vec![[0.0; PLANES]; width * height];
+            self.history.clear();
+        }
+
+        let pixels: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| framebuffer.pixel(x, y))
+            .collect();
+
+        match self.persistence {
+            Persistence::Blend(frames) => {
+                if self.history.len() >= frames.max(1) {
+                    self.history.pop_front();
+                }
+                self.history.push_back(pixels);
+
+                let count = self.history.len() as f32;
+                for (i, levels) in self.levels.iter_mut().enumerate() {
+                    for (plane, level) in levels.iter_mut().enumerate() {
+                        let lit = self
+                            .history
+                            .iter()
+                            .filter(|frame| frame[i] & (1 << plane) != 0)
+                            .count();
+                        *level = lit as f32 / count;
+                    }
+                }
+            }
+            Persistence::Decay(decay) => {
+                for (levels, pixel) in self.levels.iter_mut().zip(pixels) {
+                    for (plane, level) in levels.iter_mut().enumerate() {
+                        *level = if pixel & (1 << plane) != 0 {
+                            1.0
+                        } else {
+                            *level * decay
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    // RGB24 frame, mixing the palette colours by how lit each plane is
+    pub fn render(&self, palette: &Palette) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(self.levels.len() * 3);
+
+        for levels in &self.levels {
+            let mut colour = [0.0; 3];
+            for (index, entry) in palette.iter().enumerate() {
+                let weight: f32 = levels
+                    .iter()
+                    .enumerate()
+                    .map(|(plane, level)| {
+                        if index & (1 << plane) != 0 {
+                            *level
+                        } else {
+                            1.0 - level
+                        }
+                    })
+                    .product();
+                for channel in 0..3 {
+                    colour[channel] += weight * entry[channel] as f32;
+                }
+            }
+            frame.extend(colour.iter().map(|c| c.round() as u8));
+        }
+
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::DEFAULT_PALETTE;
+
+    #[test]
+    fn test_decay() {
+        let mut framebuffer = Framebuffer::new();
+        let mut filter = PersistenceFilter::new(Persistence::Decay(0.5));
+
+        framebuffer.draw_sprite(0, 0, 0, &[0x80]);
+        filter.update(&framebuffer);
+        assert_eq!(filter.render(&DEFAULT_PALETTE)[..3], [0xFF, 0xFF, 0xFF]);
+
+        framebuffer.draw_sprite(0, 0, 0, &[0x80]);
+        filter.update(&framebuffer);
+        assert_eq!(filter.render(&DEFAULT_PALETTE)[..3], [0x80, 0x80, 0x80]);
+
+        filter.update(&framebuffer);
+        assert_eq!(filter.render(&DEFAULT_PALETTE)[..3], [0x40, 0x40, 0x40]);
+    }
+
+    #[test]
+    fn test_blend() {
+        let mut framebuffer = Framebuffer::new();
+        let mut filter = PersistenceFilter::new(Persistence::Blend(2));
+
+        framebuffer.draw_sprite(0, 0, 0, &[0x80]);
+        filter.update(&framebuffer);
+
+        // A pixel flickering every frame looks half lit
+        for _ in 0..3 {
+            framebuffer.draw_sprite(0, 0, 0, &[0x80]);
+            filter.update(&framebuffer);
+            assert_eq!(filter.render(&DEFAULT_PALETTE)[..3], [0x80, 0x80, 0x80]);
+        }
+        assert_eq!((filter.width(), filter.height()), (64, 32));
+    }
+}
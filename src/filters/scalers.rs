@@ -0,0 +1,155 @@
+/*
+Pixel art scalers.
+
+Scale2x and Scale3x (also known as AdvMAME2x/3x) enlarge an image while
+rounding off diagonal edges, by comparing each pixel with its neighbours.
+Pixels past the edge of the image repeat the border.
+*/
+
+type Pixel = [u8; 3];
+
+struct Image<'a> {
+    rgb: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+impl Image<'_> {
+    fn get(&self, x: isize, y: isize) -> Pixel {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        let i = (y * self.width + x) * 3;
+        [self.rgb[i], self.rgb[i + 1], self.rgb[i + 2]]
+    }
+}
+
+// Doubles the size of an RGB24 image
+pub fn scale2x(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    scale_with(rgb, width, height, 2, |image, x, y| {
+        let e = image.get(x, y);
+        let b = image.get(x, y - 1);
+        let d = image.get(x - 1, y);
+        let f = image.get(x + 1, y);
+        let h = image.get(x, y + 1);
+
+        if b == h || d == f {
+            return vec![e; 4];
+        }
+        vec![
+            if d == b { d } else { e },
+            if b == f { f } else { e },
+            if d == h { d } else { e },
+            if h == f { f } else { e },
+        ]
+    })
+}
+
+// Triples the size of an RGB24 image
+pub fn scale3x(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    scale_with(rgb, width, height, 3, |image, x, y| {
+        let a = image.get(x - 1, y - 1);
+        let b = image.get(x, y - 1);
+        let c = image.get(x + 1, y - 1);
+        let d = image.get(x - 1, y);
+        let e = image.get(x, y);
+        let f = image.get(x + 1, y);
+        let g = image.get(x - 1, y + 1);
+        let h = image.get(x, y + 1);
+        let i = image.get(x + 1, y + 1);
+
+        if b == h || d == f {
+            return vec![e; 9];
+        }
+        vec![
+            if d == b { d } else { e },
+            if (d == b && e != c) || (b == f && e != a) {
+                b
+            } else {
+                e
+            },
+            if b == f { f } else { e },
+            if (d == b && e != g) || (d == h && e != a) {
+                d
+            } else {
+                e
+            },
+            e,
+            if (b == f && e != i) || (h == f && e != c) {
+                f
+            } else {
+                e
+            },
+            if d == h { d } else { e },
+            if (d == h && e != i) || (h == f && e != g) {
+                h
+            } else {
+                e
+            },
+            if h == f { f } else { e },
+        ]
+    })
+}
+
+// Builds a factor times larger image, `block` returns the factor x factor
+// pixels a source pixel turns into, row by row
+fn scale_with<F>(rgb: &[u8], width: usize, height: usize, factor: usize, block: F) -> Vec<u8>
+where
+    F: Fn(&Image, isize, isize) -> Vec<Pixel>,
+{
+    let image = Image { rgb, width, height };
+    let out_width = width * factor;
+    let mut out = vec![0; rgb.len() * factor * factor];
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixels = block(&image, x as isize, y as isize);
+            for (n, pixel) in pixels.iter().enumerate() {
+                let out_x = x * factor + n % factor;
+                let out_y = y * factor + n / factor;
+                let i = (out_y * out_width + out_x) * 3;
+                out[i..i + 3].copy_from_slice(pixel);
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: Pixel = [0xFF, 0xFF, 0xFF];
+    const K: Pixel = [0x00, 0x00, 0x00];
+
+    fn image(pixels: &[Pixel]) -> Vec<u8> {
+        pixels.concat()
+    }
+
+    #[test]
+    fn test_scale2x() {
+        // The diagonal's corners are cut instead of staircased
+        let diagonal = image(&[W, K, K, W]);
+        let scaled = scale2x(&diagonal, 2, 2);
+
+        assert_eq!(scaled.len(), 4 * 4 * 3);
+        assert_eq!(
+            scaled,
+            image(&[W, W, K, K, W, K, W, K, K, W, K, W, K, K, W, W])
+        );
+
+        // Flat areas are just enlarged
+        assert_eq!(scale2x(&image(&[W]), 1, 1), image(&[W, W, W, W]));
+    }
+
+    #[test]
+    fn test_scale3x() {
+        let diagonal = image(&[W, K, K, W]);
+        let scaled = scale3x(&diagonal, 2, 2);
+
+        assert_eq!(scaled.len(), 6 * 6 * 3);
+        // The centre of each source pixel keeps its colour
+        assert_eq!(scaled[(6 + 1) * 3..(6 + 1) * 3 + 3], W);
+        assert_eq!(scaled[(6 + 4) * 3..(6 + 4) * 3 + 3], K);
+    }
+}
@@ -0,0 +1,161 @@
+/*
+One bit per pixel framebuffer.
+
+Each bitplane stores a row of pixels in a u128 with pixel x in bit x, enough
+for the widest supported resolution of 128 pixels. A pixel's colour index is
+made up of one bit from each plane, turning indices into colours is left to
+the display.
+*/
+
+pub const PLANES: usize = 2;
+pub const MAX_HEIGHT: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    // Standard CHIP-8
+    Low,
+    // HIRES CHIP-8, two pages of the standard screen
+    Tall,
+    // SUPER-CHIP and XO-CHIP high resolution mode
+    High,
+}
+
+impl Resolution {
+    pub fn width(&self) -> usize {
+        match self {
+            Resolution::Low | Resolution::Tall => 64,
+            Resolution::High => 128,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self {
+            Resolution::Low => 32,
+            Resolution::Tall | Resolution::High => 64,
+        }
+    }
+}
+
+pub struct Framebuffer {
+    resolution: Resolution,
+    planes: [[u128; MAX_HEIGHT]; PLANES],
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Framebuffer {
+            resolution: Resolution::Low,
+            planes: [[0; MAX_HEIGHT]; PLANES],
+        }
+    }
+
+    // Switching resolution clears the screen
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        self.resolution = resolution;
+        self.planes = [[0; MAX_HEIGHT]; PLANES];
+    }
+
+    pub fn width(&self) -> usize {
+        self.resolution.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.resolution.height()
+    }
+
+    // Clears the planes set in the mask
+    pub fn clear(&mut self, plane_mask: u8) {
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if plane_mask & (1 << plane) != 0 {
+                *rows = [0; MAX_HEIGHT];
+            }
+        }
+    }
+
+    pub fn is_set(&self, plane: usize, x: usize, y: usize) -> bool {
+        (self.planes[plane][y] >> x) & 1 == 1
+    }
+
+    // Colour index of a pixel, bit n is set when plane n is lit
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        (0..PLANES).fold(0, |index, plane| {
+            index | (self.is_set(plane, x, y) as u8) << plane
+        })
+    }
+
+    // XORs an 8 pixel wide sprite onto a plane, wrapping around the screen edges.
+    // Returns true if any lit pixel was turned off
+    pub fn draw_sprite(&mut self, plane: usize, x: usize, y: usize, rows: &[u8]) -> bool {
+        let (width, height) = (self.width(), self.height());
+        let mut collision = false;
+
+        for (row, bits) in rows.iter().enumerate() {
+            let py = (y + row) % height;
+            for bit in 0..8 {
+                if (bits >> (7 - bit)) & 1 == 0 {
+                    continue;
+                }
+                let px = (x + bit) % width;
+                collision |= self.is_set(plane, px, py);
+                self.planes[plane][py] ^= 1 << px;
+            }
+        }
+
+        collision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draw_and_collision() {
+        let mut fb = Framebuffer::new();
+
+        assert!(!fb.draw_sprite(0, 0, 0, &[0b1010_0000]));
+        assert!(fb.is_set(0, 0, 0));
+        assert!(!fb.is_set(0, 1, 0));
+        assert!(fb.is_set(0, 2, 0));
+
+        // Redrawing erases and reports the collision
+        assert!(fb.draw_sprite(0, 0, 0, &[0b1000_0000]));
+        assert!(!fb.is_set(0, 0, 0));
+        assert!(fb.is_set(0, 2, 0));
+    }
+
+    #[test]
+    fn test_wrapping() {
+        let mut fb = Framebuffer::new();
+
+        fb.draw_sprite(0, 62, 31, &[0xFF, 0xFF]);
+        assert!(fb.is_set(0, 63, 31));
+        assert!(fb.is_set(0, 0, 31));
+        assert!(fb.is_set(0, 5, 0));
+        assert!(!fb.is_set(0, 6, 0));
+
+        fb.set_resolution(Resolution::High);
+        assert!(!fb.is_set(0, 63, 31));
+
+        fb.draw_sprite(0, 126, 63, &[0xC0 | 0x01]);
+        assert!(fb.is_set(0, 126, 63));
+        assert!(fb.is_set(0, 127, 63));
+        assert!(fb.is_set(0, 5, 63));
+    }
+
+    #[test]
+    fn test_planes() {
+        let mut fb = Framebuffer::new();
+
+        fb.draw_sprite(0, 0, 0, &[0b1100_0000]);
+        fb.draw_sprite(1, 1, 0, &[0b1100_0000]);
+
+        assert_eq!(fb.pixel(0, 0), 0b01);
+        assert_eq!(fb.pixel(1, 0), 0b11);
+        assert_eq!(fb.pixel(2, 0), 0b10);
+        assert_eq!(fb.pixel(3, 0), 0b00);
+
+        fb.clear(0b01);
+        assert_eq!(fb.pixel(1, 0), 0b10);
+    }
+}
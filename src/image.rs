@@ -0,0 +1,31 @@
+/*
+Helpers for saving RGB24 pixel buffers as images
+*/
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+// Nearest neighbour upscale by a whole number factor
+pub fn scale(rgb: &[u8], width: usize, height: usize, factor: usize) -> Vec<u8> {
+    let mut scaled = Vec::with_capacity(rgb.len() * factor * factor);
+
+    for y in 0..height * factor {
+        for x in 0..width * factor {
+            let i = ((y / factor) * width + x / factor) * 3;
+            scaled.extend_from_slice(&rgb[i..i + 3]);
+        }
+    }
+
+    scaled
+}
+
+pub fn write_png(path: &str, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgb)?;
+    Ok(())
+}
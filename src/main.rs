@@ -1,145 +1,1115 @@
 /*
-Use 'clap' for command line parsing
-    be able to specigy quirks and the desired program, also a debug output file
 add debug config mode that allows you to output call stack to file
-add sound handler
-add clock rate limiter
 abstract functions into drivers
-add colour options
 add better error handling. Don't Panic, return clean errors to the user
 
 Tests
 https://github.com/Timendus/chip8-test-suite?tab=readme-ov-file#available-tests
 */
 
+mod assembler;
+mod commands;
 mod config;
 mod constants;
 mod cpu;
+mod database;
+mod debugger;
 mod drivers;
+mod filters;
+mod framebuffer;
+mod image;
+#[cfg(feature = "sdl")]
+mod osd;
+mod palette;
+mod recorder;
+mod screenshot;
+mod settings;
 
-use config::{Config, ConfigFlags};
+use config::{parse_quirk, Platform};
 use constants::*;
-use drivers::audio_driver::AudioDriver;
-use drivers::input_driver::InputManager;
-use drivers::rom_driver::{Program, ProgramType};
+#[cfg(feature = "sdl")]
+use drivers::audio_driver::SdlAudio;
+use drivers::audio_driver::{Audio, BeepSettings, NullAudio, Pattern, Sound, WavAudio, Waveform};
+use drivers::input_driver::{parse_keypad_key, Keymap};
+use drivers::rom_driver::{Program, ProgramType, TESTS};
+use drivers::terminal_driver::{Glyphs, TerminalFrontend};
+#[cfg(feature = "sdl")]
+use drivers::video_driver::{Display, SdlDisplay};
 
-use clap::Parser;
-use cpu::CPU;
-use sdl2::event::Event;
-use sdl2::pixels::PixelFormatEnum;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+use cpu::{CpuError, CPU};
+use database::Database;
+use debugger::coverage::Coverage;
+use debugger::crash_dump;
+use debugger::dap;
+#[cfg(feature = "sdl")]
+use debugger::heatmap::{Heatmap, HEATMAP_SCALE, HEATMAP_SIZE};
+use debugger::profiler::Profiler;
+use debugger::trace::Tracer;
+use filters::persistence::{Persistence, PersistenceFilter};
+#[cfg(feature = "sdl")]
+use osd::Osd;
+use palette::Palette;
+use recorder::Recorder;
+#[cfg(feature = "sdl")]
+use sdl2::{
+    event::{Event, WindowEvent},
+    keyboard::Keycode,
+};
+use settings::{DisplaySettings, Settings};
 
 #[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "CHIP-8, SUPER-CHIP and XO-CHIP emulator",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a ROM, the default when no subcommand is given
+    Run(Box<Args>),
+
+    /// Print a disassembly of a ROM
+    Disassemble {
+        rom: String,
+
+        /// Write the disassembly to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Assemble a source file into a ROM
+    Assemble {
+        source: String,
+
+        /// ROM to write, defaults to the source file with a .ch8 extension
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Also write a source map for the debugger
+        #[arg(long)]
+        source_map: Option<String>,
+    },
+
+    /// Print the size, platform and instructions used by a ROM
+    Inspect { rom: String },
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
-    // Test Program to be used (1-8, 0=none)
-    #[arg(short, long, default_value_t = 0)]
-    test: u8,
+    /// ROM file to run
+    #[arg(conflicts_with = "test")]
+    rom: Option<String>,
+
+    /// Built in test program to run instead of a ROM, by number or name
+    #[arg(short, long)]
+    test: Option<String>,
+
+    /// List the built in test programs
+    #[arg(long)]
+    list_tests: bool,
+
+    /// Settings file to use instead of config.toml in the config directory
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+
+    /// Platform whose quirks and speed to use: chip8 (default), schip, xochip or hires
+    #[arg(long)]
+    platform: Option<String>,
+
+    /// Turn a quirk on or off, overriding the platform: shift, jump, load-store or
+    /// index-overflow, optionally followed by =on or =off. Can be repeated
+    #[arg(long, value_name = "QUIRK[=on|off]")]
+    quirk: Vec<String>,
+
+    /// Instructions run per 60Hz frame, defaults to the platform's speed
+    #[arg(long)]
+    tickrate: Option<usize>,
+
+    /// Seed for the random number generator, for repeatable runs
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// File of keypad bindings, one "<keypad key> <key name>" per line, applied over the
+    /// [keys] settings
+    #[arg(long, value_name = "FILE")]
+    keymap: Option<String>,
+
+    /// Write every executed instruction and the registers to this file, - for stderr
+    #[arg(long, value_name = "FILE")]
+    trace: Option<String>,
+
+    /// Run a Debug Adapter Protocol server on stdio instead of opening a window
+    #[arg(long)]
+    dap: bool,
+
+    /// Listen for a Debug Adapter Protocol client on this localhost port
+    #[arg(long)]
+    dap_port: Option<u16>,
+
+    /// Profile execution and write the report to this file, with folded stacks in <file>.folded
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Write a code/data coverage map to this file, with <file>.png and <file>.asm next to it
+    #[arg(long)]
+    coverage: Option<String>,
+
+    /// Show a live heatmap of memory accesses in a second window
+    #[arg(long, conflicts_with = "terminal")]
+    heatmap: bool,
+
+    /// Colour theme: classic (default), octo, amber, green or lcd. F6 cycles themes while running
+    #[arg(long)]
+    theme: Option<String>,
+
+    /// Four comma separated RRGGBB colours, replacing the theme's palette
+    #[arg(long)]
+    palette: Option<String>,
+
+    /// Foreground colour as RRGGBB
+    #[arg(long)]
+    fg: Option<String>,
+
+    /// Background colour as RRGGBB
+    #[arg(long)]
+    bg: Option<String>,
+
+    /// Initial window size as a multiple of the 64x32 screen [default: 16]
+    #[arg(long)]
+    scale: Option<u32>,
+
+    /// Only scale the screen by whole multiples, leaving a border if needed
+    #[arg(long)]
+    integer_scale: bool,
+
+    /// Start in fullscreen, F11 toggles fullscreen while running
+    #[arg(long)]
+    fullscreen: bool,
+
+    /// Show frames and instructions per second and the emulation speed, F10 toggles it
+    #[arg(long)]
+    show_fps: bool,
+
+    /// Save screenshots at their native resolution instead of the window scale, F12 takes one
+    #[arg(long)]
+    native_screenshots: bool,
+
+    /// Take a screenshot after this many frames
+    #[arg(long)]
+    screenshot_at: Option<u64>,
+
+    /// Record gameplay to a .gif, or .y4m with the beep in <file>.pcm
+    #[arg(long)]
+    record: Option<String>,
+
+    /// Stop recording after this many frames
+    #[arg(long)]
+    record_frames: Option<u64>,
+
+    /// Format of recordings started with F9: gif or y4m
+    #[arg(long, default_value = "gif")]
+    record_format: String,
+
+    /// Size of recorded pixels
+    #[arg(long, default_value_t = 4)]
+    record_scale: usize,
+
+    /// Beep pitch in Hz [default: 240]
+    #[arg(long)]
+    beep_frequency: Option<f32>,
+
+    /// Beep volume from 0 to 1 [default: 0.25]. F2 mutes, F3 and F4 change the volume while running
+    #[arg(long)]
+    volume: Option<f32>,
+
+    /// Beep waveform: square (default), sine, triangle or noise
+    #[arg(long)]
+    waveform: Option<String>,
+
+    /// Run without sound
+    #[arg(long, conflicts_with = "audio_wav")]
+    no_audio: bool,
+
+    /// Write the beep to a WAV file instead of playing it
+    #[arg(long, value_name = "FILE")]
+    audio_wav: Option<String>,
+
+    /// Play in the terminal instead of a window
+    #[arg(long)]
+    terminal: bool,
+
+    /// Draw the terminal screen with braille characters instead of half blocks
+    #[arg(long)]
+    braille: bool,
+
+    /// Comma separated display filters: scale2x, scale3x, scanlines, lcd, bloom, crt
+    #[arg(long)]
+    filters: Option<String>,
+
+    /// Reduce flicker by averaging this many frames
+    #[arg(long, conflicts_with = "phosphor")]
+    blend: Option<usize>,
+
+    /// Reduce flicker with phosphor decay, keeping this fraction (0-1) of brightness each frame
+    #[arg(long)]
+    phosphor: Option<f32>,
+}
+
+// The palette chosen on the command line followed by the other themes, in the
+// order the theme hotkey cycles through them
+fn palettes(display: &DisplaySettings) -> Result<Vec<(String, Palette)>, String> {
+    let theme = display.theme.as_deref().unwrap_or("classic");
+    let mut name = theme.to_lowercase();
+    let mut palette = palette::theme(&name).ok_or_else(|| {
+        format!(
+            "Unknown theme '{}', expected one of: {}",
+            theme,
+            palette::theme_names().join(", ")
+        )
+    })?;
+
+    if let Some(colours) = &display.palette {
+        palette = palette::parse_palette(colours)?;
+        name = "custom".to_string();
+    }
+    if let Some(bg) = &display.bg {
+        palette[0] = palette::parse_colour(bg)?;
+        name = "custom".to_string();
+    }
+    if let Some(fg) = &display.fg {
+        palette[1] = palette::parse_colour(fg)?;
+        name = "custom".to_string();
+    }
+
+    let mut palettes = vec![(name.clone(), palette)];
+    for (theme, palette) in palette::THEMES {
+        if theme != name {
+            palettes.push((theme.to_string(), palette));
+        }
+    }
+    Ok(palettes)
+}
+
+// The command line as the last settings layer
+fn cli_settings(args: &Args) -> Result<Settings, String> {
+    let mut settings = Settings {
+        platform: args.platform.clone(),
+        ..Settings::default()
+    };
+    for quirk in &args.quirk {
+        let (_, on) = parse_quirk(quirk)?;
+        let name = quirk.split('=').next().unwrap_or(quirk).to_lowercase();
+        settings.quirks.insert(name, on);
+    }
+    settings.timing.tickrate = args.tickrate;
+
+    let display = &mut settings.display;
+    display.theme = args.theme.clone();
+    display.palette = args.palette.clone();
+    display.fg = args.fg.clone();
+    display.bg = args.bg.clone();
+    display.scale = args.scale;
+    // Flags can only turn things on, leaving them off keeps the files' choice
+    display.integer_scale = args.integer_scale.then_some(true);
+    display.fullscreen = args.fullscreen.then_some(true);
+    display.show_fps = args.show_fps.then_some(true);
+    display.filters = args.filters.clone();
+    display.blend = args.blend;
+    display.phosphor = args.phosphor;
+
+    let audio = &mut settings.audio;
+    audio.enabled = args.no_audio.then_some(false);
+    audio.frequency = args.beep_frequency;
+    audio.volume = args.volume;
+    audio.waveform = args.waveform.clone();
+
+    settings.validate()?;
+    Ok(settings)
+}
+
+// Global settings, then the ROM's profile, per-ROM settings and the command line
+fn load_settings(args: &Args, rom_name: &str, profile: Settings) -> Result<Settings, String> {
+    let global = match &args.config {
+        Some(path) if !Path::new(path).exists() => {
+            return Err(format!("Settings file {} not found", path))
+        }
+        Some(path) => Some(PathBuf::from(path)),
+        None => settings::config_dir().map(|dir| dir.join("config.toml")),
+    };
+
+    let rom_path = args.rom.as_deref().map(Path::new);
+    let config_dir = settings::config_dir();
+    let settings = Settings::load_for_rom(
+        config_dir.as_deref(),
+        global.as_deref(),
+        profile,
+        rom_name,
+        rom_path,
+    )?;
+    Ok(settings.merge(cli_settings(args)?))
+}
+
+// Writes any requested profiling output before exiting
+fn write_reports(args: &Args, cpu: &CPU, profiler: Option<&Profiler>, coverage: Option<&Coverage>) {
+    if let (Some(profiler), Some(path)) = (profiler, &args.profile) {
+        if let Err(e) = profiler.write(path) {
+            eprintln!("Unable to write profile to {}: {}", path, e);
+        }
+    }
+
+    if let (Some(coverage), Some(path)) = (coverage, &args.coverage) {
+        if let Err(e) = coverage.write(path, &cpu.memory) {
+            eprintln!("Unable to write coverage to {}: {}", path, e);
+        }
+    }
+}
+
+fn start_recording(
+    path: &str,
+    cpu: &CPU,
+    palette: &Palette,
+    scale: usize,
+    beep: BeepSettings,
+) -> Option<Recorder> {
+    match Recorder::start(path, &cpu.framebuffer, palette, scale, beep) {
+        Ok(recorder) => {
+            println!("Recording to {}", path);
+            Some(recorder)
+        }
+        Err(e) => {
+            eprintln!("Unable to record to {}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn stop_recording(recorder: Option<Recorder>) {
+    if let Some(recorder) = recorder {
+        let frames = recorder.frames();
+        match recorder.finish() {
+            Ok(()) => println!("Recorded {} frames", frames),
+            Err(e) => eprintln!("Unable to finish recording: {}", e),
+        }
+    }
+}
+
+fn trace(tracer: &mut Option<Tracer>, cpu: &CPU) {
+    if let Some(t) = tracer.as_mut() {
+        if let Err(e) = t.record(cpu) {
+            eprintln!("Tracing stopped: {}", e);
+            *tracer = None;
+        }
+    }
+}
+
+fn finish_trace(tracer: Option<Tracer>) {
+    if let Some(Err(e)) = tracer.map(Tracer::finish) {
+        eprintln!("Unable to write trace: {}", e);
+    }
+}
+
+// Files written while running, shared by the window and the terminal
+struct Outputs {
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    recorder: Option<Recorder>,
+    record_frames: Option<u64>,
+    // Flicker reduction, updated every frame so recordings get it too
+    persistence: Option<PersistenceFilter>,
+    audio: Box<dyn Audio>,
+    frames: u64,
+    screenshot_at: Option<u64>,
+    screenshot_scale: usize,
+}
+
+impl Outputs {
+    // Before each instruction runs
+    fn instruction(&mut self, cpu: &CPU) {
+        trace(&mut self.tracer, cpu);
+
+        // Record the instruction about to be executed
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(cpu);
+        }
+
+        // Memory accessed by the previous instruction
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(cpu);
+        }
+    }
+
+    // Once a frame after the timers tick, returns whether a screenshot is due
+    fn frame(&mut self, cpu: &CPU, palette: &Palette) -> bool {
+        self.audio.frame(sound(cpu));
+        self.frames += 1;
+
+        if let Some(persistence) = self.persistence.as_mut() {
+            persistence.update(&cpu.framebuffer);
+        }
+
+        if let Some(rec) = self.recorder.as_mut() {
+            let result = match self.persistence.as_ref() {
+                Some(persistence) => rec.frame_rgb(
+                    &persistence.render(palette),
+                    persistence.width(),
+                    persistence.height(),
+                    sound(cpu),
+                ),
+                None => rec.frame(&cpu.framebuffer, palette, sound(cpu)),
+            };
+            if let Err(e) = result {
+                eprintln!("Recording stopped: {}", e);
+                self.recorder = None;
+            } else if self
+                .record_frames
+                .is_some_and(|frames| rec.frames() >= frames)
+            {
+                stop_recording(self.recorder.take());
+            }
+        }
+
+        self.screenshot_at == Some(self.frames)
+    }
+
+    fn finish(&mut self, args: &Args, cpu: &CPU) {
+        stop_recording(self.recorder.take());
+        finish_audio(self.audio.as_mut());
+        finish_trace(self.tracer.take());
+        write_reports(args, cpu, self.profiler.as_ref(), self.coverage.as_ref());
+    }
 }
 
+fn save_screenshot(rom_name: &str, cpu: &CPU, palette: &Palette, scale: usize) -> bool {
+    let path = screenshot::file_name(rom_name, "png");
+    match screenshot::save(&cpu.framebuffer, palette, scale, &path) {
+        Ok(()) => {
+            println!("Screenshot saved to {}", path);
+            true
+        }
+        Err(e) => {
+            eprintln!("Unable to save screenshot to {}: {}", path, e);
+            false
+        }
+    }
+}
 
-// Decrements cpu timers
-fn handle_timers(cpu: &mut CPU) {
-    if cpu.delay_timer > 0 {
-        cpu.delay_timer -= 1;
+fn sound(cpu: &CPU) -> Sound {
+    Sound {
+        beeping: cpu.sound_timer > 0,
+        pattern: cpu.audio_pattern.map(|bits| Pattern {
+            bits,
+            pitch: cpu.pitch,
+        }),
     }
+}
 
-    if cpu.sound_timer > 0 {
-        cpu.sound_timer -= 1;
+// Silence or the beep written to a WAV file. The window plays the beep with SDL
+// instead and the terminal rings its bell
+fn open_audio(args: &Args, enabled: bool, beep: BeepSettings) -> Box<dyn Audio> {
+    if !enabled {
+        return Box::new(NullAudio::new(beep));
     }
+    if let Some(path) = &args.audio_wav {
+        return match WavAudio::create(path, beep) {
+            Ok(audio) => Box::new(audio),
+            Err(e) => {
+                eprintln!("Failed to create {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+    Box::new(NullAudio::new(beep))
 }
 
-fn handle_sound(cpu: &mut CPU, audio: &AudioDriver) {
-    if cpu.sound_timer > 0 {
-        audio.start_beep()
-    } else {
-        audio.stop_beep()
+fn finish_audio(audio: &mut dyn Audio) {
+    if let Err(e) = audio.finish() {
+        eprintln!("Failed to write audio: {}", e);
+    }
+}
+
+fn report_crash(cpu: &CPU, error: &CpuError, palette: &Palette) {
+    eprintln!("Emulation stopped: {}", error);
+
+    match crash_dump::write(cpu, error, palette) {
+        Ok(path) => eprintln!("Crash report written to {}", path),
+        Err(e) => eprintln!("Unable to write crash report: {}", e),
+    }
+}
+
+// Runs in the terminal at tickrate instructions a frame until Esc is pressed
+fn run_terminal(
+    cpu: &mut CPU,
+    glyphs: Glyphs,
+    rom_name: &str,
+    palette: &Palette,
+    tickrate: usize,
+    keymap: Keymap,
+    outputs: &mut Outputs,
+) -> Result<(), String> {
+    let mut terminal = TerminalFrontend::new(glyphs, keymap).map_err(|e| e.to_string())?;
+
+    let frame_time = Duration::from_micros(16666);
+    let mut next_frame = Instant::now();
+    let mut second_start = Instant::now();
+    let mut executed = 0;
+    let mut ips = 0;
+    let mut beeping = false;
+    let mut last_status = String::new();
+
+    loop {
+        let input = terminal.poll_input().map_err(|e| e.to_string())?;
+        if input.quit {
+            return Ok(());
+        }
+        cpu.input.set_keys(input.keys);
+
+        for _ in 0..tickrate {
+            outputs.instruction(cpu);
+            if let Err(e) = cpu.step() {
+                // Leave the alternate screen so the error stays visible
+                drop(terminal);
+                report_crash(cpu, &e, palette);
+                return Err(format!("{} crashed", rom_name));
+            }
+        }
+        executed += tickrate;
+        cpu.tick_timers();
+        if outputs.frame(cpu, palette) {
+            save_screenshot(rom_name, cpu, palette, outputs.screenshot_scale);
+        }
+
+        if cpu.sound_timer > 0 && !beeping {
+            terminal.beep().map_err(|e| e.to_string())?;
+        }
+        beeping = cpu.sound_timer > 0;
+
+        if second_start.elapsed() >= Duration::from_secs(1) {
+            ips = executed;
+            executed = 0;
+            second_start = Instant::now();
+        }
+
+        let status = format!(
+            "{}  {} IPS ({}%)  Esc quits",
+            rom_name,
+            ips,
+            ips * 100 / (tickrate * 60)
+        );
+        // Only redraw when the program drew something or the status changed
+        if cpu.take_screen_update() || status != last_status {
+            terminal
+                .present(&cpu.framebuffer, palette, &status)
+                .map_err(|e| e.to_string())?;
+            last_status = status;
+        }
+
+        next_frame += frame_time;
+        match next_frame.checked_duration_since(Instant::now()) {
+            Some(wait) => thread::sleep(wait),
+            // Running behind, don't try to catch up
+            None => next_frame = Instant::now(),
+        }
     }
 }
 
 fn main() {
-    let args = Args::parse();
-
-    // Init SDL2
-    let sdl2_context = sdl2::init().unwrap();
-    let video_subsystem = sdl2_context.video().unwrap();
-    let window = video_subsystem
-        .window(
-            "Chip8 Emulator",
-            X_PIXELS * PIXEL_SIZE,
-            Y_PIXELS * PIXEL_SIZE,
-        )
-        .position_centered()
-        .build()
-        .unwrap();
+    let cli = Cli::parse();
 
-    let mut canvas = window.into_canvas().build().unwrap();
-    canvas
-        .set_scale(PIXEL_SIZE as f32, PIXEL_SIZE as f32)
-        .unwrap();
+    let result = match cli.command {
+        None => return run(cli.args),
+        Some(Command::Run(args)) => return run(*args),
+        Some(Command::Disassemble { rom, output }) => {
+            commands::disassemble(&rom, output.as_deref())
+        }
+        Some(Command::Assemble {
+            source,
+            output,
+            source_map,
+        }) => commands::assemble(&source, output.as_deref(), source_map.as_deref()),
+        Some(Command::Inspect { rom }) => commands::inspect(&rom),
+    };
 
-    let creator = canvas.texture_creator();
-    let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, X_PIXELS, Y_PIXELS)
-        .unwrap();
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut event_pump = sdl2_context.event_pump().unwrap();
-    let audio = AudioDriver::new(&sdl2_context);
+fn run(args: Args) {
+    if args.dap || args.dap_port.is_some() {
+        let result = match args.dap_port {
+            Some(port) => dap::serve_tcp(port),
+            None => dap::serve_stdio(),
+        };
 
-    // -----------------------------------------------------------------------------------
+        if let Err(e) = result {
+            eprintln!("Debug adapter error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    // Init emulator
-    let mut cpu = CPU::new(Config::default());
+    if args.list_tests {
+        for (name, bytes) in TESTS {
+            println!("{:<14} {} bytes", name, bytes.len());
+        }
+        return;
+    }
+
+    // Without SDL the terminal is the only frontend
+    #[cfg(not(feature = "sdl"))]
+    if !args.terminal {
+        eprintln!("Built without SDL, pass --terminal to play in the terminal");
+        std::process::exit(2);
+    }
+
+    let program_path = match (&args.rom, &args.test) {
+        (Some(path), _) => ProgramType::Path(path.clone()),
+        (None, Some(test)) => ProgramType::Test(test.clone()),
+        (None, None) => {
+            eprintln!("No ROM given, pass a ROM file or --test <number or name>");
+            std::process::exit(2);
+        }
+    };
+
+    let program = Program::load(program_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let rom_name = program.name.clone();
+
+    let metadata = match Database::load() {
+        Ok(database) => program.metadata(&database),
+        Err(e) => {
+            eprintln!("Warning: unable to read the ROM database, {}", e);
+            None
+        }
+    };
+    let profile = database::profile(metadata.as_ref(), args.rom.as_deref().map(Path::new));
 
-    let program_path: ProgramType;
+    let settings = load_settings(&args, &rom_name, profile).unwrap_or_else(|e| {
+        eprintln!("Invalid settings, {}", e);
+        std::process::exit(2);
+    });
+    let display_settings = &settings.display;
+
+    let palettes = palettes(display_settings).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+
+    let default_beep = BeepSettings::default();
+    let beep = settings
+        .audio
+        .waveform
+        .as_deref()
+        .map_or(Ok(default_beep.waveform), Waveform::parse)
+        .map(|waveform| BeepSettings {
+            frequency: settings.audio.frequency.unwrap_or(default_beep.frequency),
+            volume: settings.audio.volume.unwrap_or(default_beep.volume),
+            waveform,
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        });
+
+    let (platform, config, tickrate) = settings.machine().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    match program.check(platform) {
+        Ok(warnings) => {
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 
-    if args.test > 0 {
-        program_path = ProgramType::Test(args.test);
-    } else {
-        program_path = ProgramType::Path("./roms/IBM Logo.ch8".to_string());
+    let mut keymap = Keymap::default();
+    for (keypad, name) in &settings.keys {
+        // Validated with the settings
+        keymap.bind(parse_keypad_key(keypad).unwrap(), name);
+    }
+    if let Some(path) = &args.keymap {
+        if let Err(e) = keymap.apply_file(path) {
+            eprintln!("Invalid keymap {}", e);
+            std::process::exit(2);
+        }
+    }
+    if let Some(metadata) = metadata
+        .as_ref()
+        .filter(|metadata| !metadata.keys.is_empty())
+    {
+        let hints: Vec<String> = metadata
+            .keys
+            .iter()
+            .filter(|(_, keypad)| **keypad < 16)
+            .map(|(action, keypad)| format!("{} = {}", action, keymap.key_name(*keypad)))
+            .collect();
+        println!("Keys: {}", hints.join(", "));
     }
 
-    let program = Program::new(program_path);
+    let tracer = args.trace.as_ref().map(|path| {
+        Tracer::create(path).unwrap_or_else(|e| {
+            eprintln!("Unable to trace to {}: {}", path, e);
+            std::process::exit(1);
+        })
+    });
+
+    // Init emulator
+    let mut cpu = CPU::new(config);
+    if let Some(seed) = args.seed {
+        cpu.seed(seed);
+    }
+    cpu.input.keymap = keymap.clone();
+
+    let coverage = args
+        .coverage
+        .as_ref()
+        .map(|_| Coverage::new(program.bytes.len()));
+    cpu.trace_memory = coverage.is_some() || args.heatmap;
 
     cpu.load_program(program.bytes);
+    if platform == Platform::Hires {
+        cpu.start_hires();
+    }
 
-    let mut frame_start = std::time::Instant::now();
-    let mut timer_count = std::time::Duration::from_secs(0);
+    let recorder = args
+        .record
+        .as_ref()
+        .and_then(|path| start_recording(path, &cpu, &palettes[0].1, args.record_scale, beep));
 
-    let mut input = InputManager::new();
+    let scale = display_settings.scale.unwrap_or(PIXEL_SIZE).max(1);
+    let audio_enabled = settings.audio.enabled.unwrap_or(true);
+    let mut outputs = Outputs {
+        tracer,
+        profiler: args.profile.as_ref().map(|_| Profiler::new()),
+        coverage,
+        recorder,
+        record_frames: args.record_frames,
+        persistence: match (display_settings.blend, display_settings.phosphor) {
+            (Some(frames), _) => Some(Persistence::Blend(frames)),
+            (_, Some(decay)) => Some(Persistence::Decay(decay.clamp(0.0, 1.0))),
+            _ => None,
+        }
+        .map(PersistenceFilter::new),
+        audio: open_audio(&args, audio_enabled, beep),
+        frames: 0,
+        screenshot_at: args.screenshot_at,
+        screenshot_scale: if args.native_screenshots {
+            1
+        } else {
+            scale as usize
+        },
+    };
+
+    if args.terminal {
+        let glyphs = if args.braille {
+            Glyphs::Braille
+        } else {
+            Glyphs::HalfBlock
+        };
+        let result = run_terminal(
+            &mut cpu,
+            glyphs,
+            &rom_name,
+            &palettes[0].1,
+            tickrate,
+            keymap,
+            &mut outputs,
+        );
+        outputs.finish(&args, &cpu);
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 
     // -----------------------------------------------------------------------------------
 
-    // Run emulator
-    cpu.run_with_callback(move |cpu| {
-        // Handle program timing
-        let frame_end = std::time::Instant::now();
+    #[cfg(feature = "sdl")]
+    if !args.terminal {
+        let mut palette_index = 0;
+        let display_filters =
+            filters::parse_filters(display_settings.filters.as_deref().unwrap_or(""))
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(2);
+                });
 
-        let frame_time = frame_end - frame_start;
+        // Init SDL2
+        let sdl2_context = sdl2::init().unwrap();
+        let video_subsystem = sdl2_context.video().unwrap();
+        let title = match &metadata {
+            Some(metadata) => format!("Chip8 Emulator - {}", metadata.title),
+            None => "Chip8 Emulator".to_string(),
+        };
+        let mut display =
+            SdlDisplay::new(&video_subsystem, &title, X_PIXELS, Y_PIXELS, scale).unwrap();
+        display.set_integer_scaling(display_settings.integer_scale.unwrap_or(false));
+        display.set_filters(display_filters);
+        if display_settings.fullscreen.unwrap_or(false) {
+            display.toggle_fullscreen();
+        }
 
-        timer_count += frame_time;
+        let main_window_id = display.window_id();
 
-        frame_start = std::time::Instant::now();
+        // Memory heatmap is shown in its own window
+        let mut heatmap = args.heatmap.then(Heatmap::new);
+        let mut heatmap_display = args.heatmap.then(|| {
+            SdlDisplay::new(
+                &video_subsystem,
+                "Memory Heatmap",
+                HEATMAP_SIZE,
+                HEATMAP_SIZE,
+                HEATMAP_SCALE,
+            )
+            .unwrap()
+        });
 
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. } => std::process::exit(0),
-                _ => {}
+        let mut event_pump = sdl2_context.event_pump().unwrap();
+        if audio_enabled && args.audio_wav.is_none() {
+            match SdlAudio::new(&sdl2_context, beep) {
+                Ok(audio) => outputs.audio = Box::new(audio),
+                Err(e) => eprintln!("Warning: no audio, failed to open sound device: {}", e),
             }
-            input.handle_keyboard_input(event);
         }
 
-        // Update cpu timers @ 60hz
-        if timer_count >= std::time::Duration::from_micros(16666) {
-            handle_timers(cpu);
-            timer_count = std::time::Duration::from_secs(0);
-        }
+        let mut frame_start = std::time::Instant::now();
+        let mut timer_count = std::time::Duration::from_secs(0);
+
+        // F8 pauses
+        let mut osd = Osd::new(display_settings.show_fps.unwrap_or(false));
+        let mut overlay = Vec::new();
+
+        // -----------------------------------------------------------------------------------
 
-        handle_sound(cpu, &audio);
+        let frame_length = Duration::from_micros(16666);
+        let mut frame_instructions = 0;
 
-        // Only updates screen if draw method is called
-        if cpu.update_screen {
-            texture.update(None, &cpu.vram, 64 * 3).unwrap();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
+        // Run emulator
+        let result = cpu.run_with_callback(|cpu| {
+            // Run at most tickrate instructions a frame, then wait for the next one
+            if frame_instructions >= tickrate {
+                let elapsed = timer_count + frame_start.elapsed();
+                if elapsed < frame_length {
+                    thread::sleep(frame_length - elapsed);
+                }
+            }
+
+            // Handle program timing
+            let frame_end = std::time::Instant::now();
+
+            let frame_time = frame_end - frame_start;
+
+            timer_count += frame_time;
+
+            frame_start = std::time::Instant::now();
+
+            let mut quit = false;
+            let mut take_screenshot = false;
+            let mut toggle_recording = false;
+
+            // Paused, only events are handled until unpaused
+            loop {
+                for event in event_pump.poll_iter() {
+                    match &event {
+                        Event::Quit { .. } => quit = true,
+                        Event::Window {
+                            window_id,
+                            win_event: WindowEvent::Close,
+                            ..
+                        } => {
+                            // With the heatmap open, closing a window doesn't quit on its own
+                            if Some(*window_id) == main_window_id {
+                                quit = true;
+                            } else if let Some(heatmap_display) = heatmap_display.as_mut() {
+                                heatmap_display.hide();
+                            }
+                        }
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F6),
+                            repeat: false,
+                            ..
+                        } => {
+                            palette_index = (palette_index + 1) % palettes.len();
+                            osd.message(format!("Palette {}", palettes[palette_index].0));
+                            cpu.update_screen = true;
+                        }
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F11),
+                            repeat: false,
+                            ..
+                        } => display.toggle_fullscreen(),
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F12),
+                            repeat: false,
+                            ..
+                        } => take_screenshot = true,
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F9),
+                            repeat: false,
+                            ..
+                        } => toggle_recording = true,
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F8),
+                            repeat: false,
+                            ..
+                        } => {
+                            osd.paused = !osd.paused;
+                            if osd.paused {
+                                outputs.audio.pause();
+                            }
+                            cpu.update_screen = true;
+                        }
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F10),
+                            repeat: false,
+                            ..
+                        } => {
+                            osd.show_stats = !osd.show_stats;
+                            cpu.update_screen = true;
+                        }
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F2),
+                            repeat: false,
+                            ..
+                        } => {
+                            let muted = outputs.audio.toggle_mute();
+                            osd.message(if muted { "Muted" } else { "Unmuted" });
+                        }
+                        Event::KeyDown {
+                            keycode: Some(keycode @ (Keycode::F3 | Keycode::F4)),
+                            ..
+                        } => {
+                            let step = if *keycode == Keycode::F3 { -0.1 } else { 0.1 };
+                            let volume = outputs.audio.volume() + step;
+                            outputs.audio.set_volume(volume);
+                            osd.message(format!(
+                                "Volume {}%",
+                                (outputs.audio.volume() * 100.0).round()
+                            ));
+                        }
+                        // Redraw after the window is resized, exposed or goes fullscreen
+                        Event::Window {
+                            window_id,
+                            win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed,
+                            ..
+                        } if Some(*window_id) == main_window_id => cpu.update_screen = true,
+                        _ => {}
+                    }
+                    cpu.input.handle_keyboard_input(event);
+                }
+
+                if quit || !osd.paused {
+                    break;
+                }
+                if cpu.take_screen_update() {
+                    display.set_overlay(osd.overlay());
+                    display.present_framebuffer(&cpu.framebuffer, &palettes[palette_index].1);
+                }
+                thread::sleep(Duration::from_millis(16));
+                frame_start = Instant::now();
+            }
+
+            if quit {
+                outputs.finish(&args, cpu);
+                std::process::exit(0)
+            }
+
+            // Update cpu timers @ 60hz
+            if timer_count >= frame_length {
+                frame_instructions = 0;
+                cpu.tick_timers();
+                timer_count = std::time::Duration::from_secs(0);
+                take_screenshot |= outputs.frame(cpu, &palettes[palette_index].1);
+
+                if let (Some(heatmap), Some(heatmap_display)) =
+                    (heatmap.as_mut(), heatmap_display.as_mut())
+                {
+                    heatmap.decay();
+                    heatmap_display.present(&heatmap.render(), HEATMAP_SIZE, HEATMAP_SIZE);
+                }
+
+                // Messages appearing or expiring need a redraw too
+                osd.update(tickrate * 60);
+                osd.recording = outputs.recorder.is_some();
+                let new_overlay = osd.overlay();
+                if new_overlay != overlay {
+                    overlay = new_overlay;
+                    display.set_overlay(overlay.clone());
+                    cpu.update_screen = true;
+                }
+
+                // Present at most once a frame, however many times the program drew.
+                // Fading pixels change every frame, drawn or not
+                let updated = cpu.take_screen_update();
+                if let Some(persistence) = outputs.persistence.as_ref() {
+                    display.present(
+                        &persistence.render(&palettes[palette_index].1),
+                        persistence.width() as u32,
+                        persistence.height() as u32,
+                    );
+                    osd.count_frame();
+                } else if updated {
+                    display.present_framebuffer(&cpu.framebuffer, &palettes[palette_index].1);
+                    osd.count_frame();
+                }
+            }
+
+            let palette = &palettes[palette_index].1;
+            if take_screenshot && save_screenshot(&rom_name, cpu, palette, outputs.screenshot_scale)
+            {
+                osd.message("Screenshot saved");
+            }
+
+            if toggle_recording {
+                if outputs.recorder.is_some() {
+                    stop_recording(outputs.recorder.take());
+                    osd.message("Recording stopped");
+                } else {
+                    let path = screenshot::file_name(&rom_name, &args.record_format);
+                    outputs.recorder =
+                        start_recording(&path, cpu, palette, args.record_scale, beep);
+                    if outputs.recorder.is_some() {
+                        osd.message("Recording");
+                    }
+                }
+            }
+
+            osd.count_instruction();
+            frame_instructions += 1;
+            outputs.instruction(cpu);
+
+            if let Some(heatmap) = heatmap.as_mut() {
+                heatmap.record(cpu);
+            }
+        });
+
+        // Only returns if an instruction failed
+        if let Err(e) = result {
+            report_crash(&cpu, &e, &palettes[palette_index].1);
+            outputs.finish(&args, &cpu);
+            std::process::exit(1);
         }
-    });
+    }
 }
@@ -0,0 +1,279 @@
+/*
+On-screen display.
+
+Transient messages, a speed counter, and pause and recording indicators are
+drawn over the frame after it leaves the framebuffer, so the emulated screen is
+never touched. Text uses a tiny 3x5 font, lower case letters are drawn as
+capitals. Frames are enlarged to at least OVERLAY_WIDTH pixels first so the
+text stays small.
+*/
+
+use std::time::{Duration, Instant};
+
+use crate::image;
+
+const OVERLAY_WIDTH: usize = 512;
+const TEXT_SCALE: usize = 2;
+const MESSAGE_TIME: Duration = Duration::from_secs(2);
+const TEXT_COLOUR: [u8; 3] = [0xFF, 0xFF, 0xFF];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Anchor {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    Centre,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverlayText {
+    pub text: String,
+    pub anchor: Anchor,
+}
+
+// Rows of a glyph, bit 2 is the leftmost pixel
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '?' => [0b110, 0b001, 0b010, 0b000, 0b010],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        ' ' => [0; 5],
+        _ => [0b111, 0b101, 0b101, 0b101, 0b111],
+    }
+}
+
+// Draws text with its top left corner at x, y on a darkened box
+fn draw_text(rgb: &mut [u8], width: usize, height: usize, x: usize, y: usize, text: &str) {
+    let (box_width, box_height) = text_size(text);
+
+    for py in y.saturating_sub(TEXT_SCALE)..(y + box_height + TEXT_SCALE).min(height) {
+        for px in x.saturating_sub(TEXT_SCALE)..(x + box_width + TEXT_SCALE).min(width) {
+            let i = (py * width + px) * 3;
+            for channel in &mut rgb[i..i + 3] {
+                *channel /= 3;
+            }
+        }
+    }
+
+    for (n, c) in text.chars().enumerate() {
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..TEXT_SCALE {
+                    for dx in 0..TEXT_SCALE {
+                        let px = x + (n * 4 + col) * TEXT_SCALE + dx;
+                        let py = y + row * TEXT_SCALE + dy;
+                        if px < width && py < height {
+                            let i = (py * width + px) * 3;
+                            rgb[i..i + 3].copy_from_slice(&TEXT_COLOUR);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn text_size(text: &str) -> (usize, usize) {
+    let chars = text.chars().count();
+    ((chars * 4).saturating_sub(1) * TEXT_SCALE, 5 * TEXT_SCALE)
+}
+
+// Draws the overlay over a copy of the frame, returning it and its size
+pub fn draw_overlay(
+    frame: &[u8],
+    width: usize,
+    height: usize,
+    overlay: &[OverlayText],
+) -> (Vec<u8>, usize, usize) {
+    let factor = OVERLAY_WIDTH.div_ceil(width).max(1);
+    let mut rgb = image::scale(frame, width, height, factor);
+    let (width, height) = (width * factor, height * factor);
+    let margin = TEXT_SCALE * 3;
+
+    for item in overlay {
+        let (text_width, text_height) = text_size(&item.text);
+        let (x, y) = match item.anchor {
+            Anchor::TopLeft => (margin, margin),
+            Anchor::TopRight => (width.saturating_sub(text_width + margin), margin),
+            Anchor::BottomLeft => (margin, height.saturating_sub(text_height + margin)),
+            Anchor::Centre => (
+                width.saturating_sub(text_width) / 2,
+                height.saturating_sub(text_height) / 2,
+            ),
+        };
+        draw_text(&mut rgb, width, height, x, y, &item.text);
+    }
+
+    (rgb, width, height)
+}
+
+pub struct Osd {
+    message: Option<(String, Instant)>,
+    pub show_stats: bool,
+    pub paused: bool,
+    pub recording: bool,
+    stats: String,
+    frames: usize,
+    instructions: usize,
+    counting_since: Instant,
+}
+
+impl Osd {
+    pub fn new(show_stats: bool) -> Self {
+        Osd {
+            message: None,
+            show_stats,
+            paused: false,
+            recording: false,
+            stats: String::new(),
+            frames: 0,
+            instructions: 0,
+            counting_since: Instant::now(),
+        }
+    }
+
+    // Shows a message for a couple of seconds, replacing any current one
+    pub fn message(&mut self, text: impl Into<String>) {
+        self.message = Some((text.into(), Instant::now()));
+    }
+
+    pub fn count_instruction(&mut self) {
+        self.instructions += 1;
+    }
+
+    pub fn count_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    // Refreshes the counters once a second, `clock_speed` is 100%
+    pub fn update(&mut self, clock_speed: usize) {
+        let elapsed = self.counting_since.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+
+        let secs = elapsed.as_secs_f32();
+        let fps = self.frames as f32 / secs;
+        let ips = self.instructions as f32 / secs;
+        self.stats = format!(
+            "{:.0} FPS {:.0} IPS {:.0}%",
+            fps,
+            ips,
+            ips * 100.0 / clock_speed as f32
+        );
+
+        self.frames = 0;
+        self.instructions = 0;
+        self.counting_since = Instant::now();
+    }
+
+    pub fn overlay(&self) -> Vec<OverlayText> {
+        let mut overlay = Vec::new();
+
+        if self.show_stats && !self.stats.is_empty() {
+            overlay.push(OverlayText {
+                text: self.stats.clone(),
+                anchor: Anchor::TopLeft,
+            });
+        }
+        if self.recording {
+            overlay.push(OverlayText {
+                text: "REC".to_string(),
+                anchor: Anchor::TopRight,
+            });
+        }
+        if self.paused {
+            overlay.push(OverlayText {
+                text: "PAUSED".to_string(),
+                anchor: Anchor::Centre,
+            });
+        }
+        if let Some((message, shown_at)) = &self.message {
+            if shown_at.elapsed() < MESSAGE_TIME {
+                overlay.push(OverlayText {
+                    text: message.clone(),
+                    anchor: Anchor::BottomLeft,
+                });
+            }
+        }
+
+        overlay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlay() {
+        let mut osd = Osd::new(false);
+        assert!(osd.overlay().is_empty());
+
+        osd.message("State 2 saved");
+        osd.paused = true;
+        let overlay = osd.overlay();
+        assert_eq!(overlay.len(), 2);
+        assert_eq!(overlay[1].text, "State 2 saved");
+
+        let frame = vec![0x90; 64 * 32 * 3];
+        let (rgb, width, height) = draw_overlay(&frame, 64, 32, &overlay);
+        assert_eq!((width, height), (512, 256));
+        // The source frame is left alone
+        assert!(frame.iter().all(|c| *c == 0x90));
+        // Text is drawn on a darkened box in the bottom left corner
+        assert!(rgb.chunks(3).any(|p| p == TEXT_COLOUR));
+        let corner = ((height - 8) * width + 4) * 3;
+        assert_eq!(rgb[corner], 0x30);
+        assert_eq!(rgb[0], 0x90);
+    }
+}
@@ -0,0 +1,133 @@
+/*
+Colour palettes.
+
+A palette maps pixel colour indices to RGB. Index 0 is the background and 1 the
+foreground, 2 and 3 are only seen when a program draws to both planes.
+*/
+
+pub type Palette = [[u8; 3]; 4];
+
+pub const DEFAULT_PALETTE: Palette = [
+    [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+];
+
+pub const THEMES: [(&str, Palette); 5] = [
+    ("classic", DEFAULT_PALETTE),
+    (
+        "octo",
+        [
+            [0x99, 0x66, 0x00],
+            [0xFF, 0xCC, 0x00],
+            [0xFF, 0x66, 0x00],
+            [0x66, 0x22, 0x00],
+        ],
+    ),
+    (
+        "amber",
+        [
+            [0x1A, 0x0F, 0x00],
+            [0xFF, 0xB0, 0x00],
+            [0xB3, 0x6B, 0x00],
+            [0x66, 0x3D, 0x00],
+        ],
+    ),
+    (
+        "green",
+        [
+            [0x00, 0x1A, 0x0A],
+            [0x33, 0xFF, 0x66],
+            [0x1F, 0xA8, 0x45],
+            [0x0F, 0x5C, 0x26],
+        ],
+    ),
+    (
+        "lcd",
+        // Game Boy greens, the lightest as the background and the darkest as
+        // the foreground, with two middle shades far enough from both
+        [
+            [0x9B, 0xBC, 0x0F],
+            [0x0F, 0x38, 0x0F],
+            [0x30, 0x62, 0x30],
+            [0x65, 0x8F, 0x20],
+        ],
+    ),
+];
+
+pub fn theme(name: &str) -> Option<Palette> {
+    THEMES
+        .iter()
+        .find(|(theme, _)| theme.eq_ignore_ascii_case(name))
+        .map(|(_, palette)| *palette)
+}
+
+pub fn theme_names() -> Vec<&'static str> {
+    THEMES.iter().map(|(name, _)| *name).collect()
+}
+
+// Parses "RRGGBB", with or without a leading '#'
+pub fn parse_colour(hex: &str) -> Result<[u8; 3], String> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid colour '{}', expected RRGGBB", hex));
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).unwrap();
+    Ok([channel(0), channel(2), channel(4)])
+}
+
+// Parses four comma separated colours, from background to the colour of both planes
+pub fn parse_palette(colours: &str) -> Result<Palette, String> {
+    let colours: Vec<&str> = colours.split(',').map(str::trim).collect();
+    if colours.len() != 4 {
+        return Err(format!(
+            "Expected 4 colours in palette, got {}",
+            colours.len()
+        ));
+    }
+
+    let mut palette = [[0; 3]; 4];
+    for (entry, colour) in palette.iter_mut().zip(colours) {
+        *entry = parse_colour(colour)?;
+    }
+    Ok(palette)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_colour("#FFb000"), Ok([0xFF, 0xB0, 0x00]));
+        assert_eq!(parse_colour("102030"), Ok([0x10, 0x20, 0x30]));
+        assert!(parse_colour("#FFF").is_err());
+        assert!(parse_colour("GGGGGG").is_err());
+
+        let palette = parse_palette("000000, ffffff, #ff0000, 00ff00").unwrap();
+        assert_eq!(palette[2], [0xFF, 0, 0]);
+        assert!(parse_palette("000000,ffffff").is_err());
+
+        assert_eq!(theme("Amber"), Some(THEMES[2].1));
+        assert_eq!(theme("missing"), None);
+    }
+
+    #[test]
+    fn test_themes_distinct() {
+        for (name, palette) in THEMES {
+            for (i, a) in palette.iter().enumerate() {
+                for b in &palette[i + 1..] {
+                    // Summed over the channels
+                    let distance: u32 = (0..3).map(|c| a[c].abs_diff(b[c]) as u32).sum();
+                    assert!(
+                        distance >= 0x30,
+                        "{} has colours too close to tell apart",
+                        name
+                    );
+                }
+            }
+        }
+    }
+}
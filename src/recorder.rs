@@ -0,0 +1,417 @@
+/*
+Gameplay recording.
+
+Frames are captured once per 60hz frame. An animated GIF uses the palette as
+its colour table, and identical frames are merged with delays kept in step
+with 60hz even though GIF delays are in hundredths of a second. Frames that
+have been through the flicker reduction are RGB and get a colour table of
+their own. A Y4M recording is raw 4:4:4 video at 60fps with a raw PCM stream
+of the beep in <file>.pcm (signed 16 bit little endian, mono), for muxing with
+e.g. ffmpeg.
+*/
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::drivers::audio_driver::{Beep, BeepSettings, Sound, Tone, SAMPLE_RATE};
+use crate::framebuffer::Framebuffer;
+use crate::palette::Palette;
+
+const FPS: u64 = 60;
+
+pub struct Recorder {
+    sink: Sink,
+    // Output size, frames in other resolutions are stretched to fit
+    width: usize,
+    height: usize,
+    frames: u64,
+}
+
+enum Sink {
+    Gif(GifSink),
+    Y4m(Y4mSink),
+}
+
+// One frame at the output size
+#[derive(PartialEq)]
+enum Picture {
+    Indexed(Vec<u8>, Palette),
+    Rgb(Vec<u8>),
+}
+
+struct GifSink {
+    encoder: gif::Encoder<BufWriter<File>>,
+    palette: Palette,
+    // Frame waiting for the next different one, to know how long it was shown
+    pending: Option<Picture>,
+    pending_since: u64,
+    // Hundredths of a second written so far
+    written: u64,
+}
+
+struct Y4mSink {
+    video: BufWriter<File>,
+    audio: BufWriter<File>,
+    beep: Beep,
+}
+
+impl Recorder {
+    // The format is picked from the extension, .gif or .y4m
+    pub fn start(
+        path: &str,
+        framebuffer: &Framebuffer,
+        palette: &Palette,
+        scale: usize,
+        beep: BeepSettings,
+    ) -> io::Result<Self> {
+        let scale = scale.max(1);
+        let width = framebuffer.width() * scale;
+        let height = framebuffer.height() * scale;
+
+        let sink = if path.to_lowercase().ends_with(".y4m") {
+            let mut video = BufWriter::new(File::create(path)?);
+            writeln!(
+                video,
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444",
+                width, height, FPS
+            )?;
+            let audio = BufWriter::new(File::create(format!("{}.pcm", path))?);
+            Sink::Y4m(Y4mSink {
+                video,
+                audio,
+                beep: Beep::new(Tone::new(beep, SAMPLE_RATE)),
+            })
+        } else if path.to_lowercase().ends_with(".gif") {
+            let file = BufWriter::new(File::create(path)?);
+            let mut encoder =
+                gif::Encoder::new(file, width as u16, height as u16, &palette.concat())
+                    .map_err(io::Error::other)?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(io::Error::other)?;
+            Sink::Gif(GifSink {
+                encoder,
+                palette: *palette,
+                pending: None,
+                pending_since: 0,
+                written: 0,
+            })
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't record to {}, expected a .gif or .y4m file", path),
+            ));
+        };
+
+        Ok(Recorder {
+            sink,
+            width,
+            height,
+            frames: 0,
+        })
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Should be called once per frame
+    pub fn frame(
+        &mut self,
+        framebuffer: &Framebuffer,
+        palette: &Palette,
+        sound: Sound,
+    ) -> io::Result<()> {
+        let picture = Picture::Indexed(self.resample(framebuffer), *palette);
+        self.push(picture, sound)
+    }
+
+    // Instead of `frame`, for an RGB24 frame of the given size
+    pub fn frame_rgb(
+        &mut self,
+        rgb: &[u8],
+        width: usize,
+        height: usize,
+        sound: Sound,
+    ) -> io::Result<()> {
+        let picture = Picture::Rgb(self.resample_rgb(rgb, width, height));
+        self.push(picture, sound)
+    }
+
+    fn push(&mut self, picture: Picture, sound: Sound) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Gif(gif) => {
+                if gif.pending.as_ref() != Some(&picture) {
+                    gif.flush(self.frames, self.width, self.height)?;
+                    gif.pending = Some(picture);
+                    gif.pending_since = self.frames;
+                }
+            }
+            Sink::Y4m(y4m) => y4m.write(&picture, sound)?,
+        }
+
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Gif(gif) => gif.flush(self.frames, self.width, self.height),
+            Sink::Y4m(y4m) => {
+                y4m.video.flush()?;
+                y4m.audio.flush()
+            }
+        }
+    }
+
+    // Colour indices at the output size
+    fn resample(&self, framebuffer: &Framebuffer) -> Vec<u8> {
+        let (width, height) = (framebuffer.width(), framebuffer.height());
+        let mut indices = Vec::with_capacity(self.width * self.height);
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                indices.push(framebuffer.pixel(x * width / self.width, y * height / self.height));
+            }
+        }
+
+        indices
+    }
+
+    // RGB24 at the output size
+    fn resample_rgb(&self, rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.width * self.height * 3);
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = (y * height / self.height * width + x * width / self.width) * 3;
+                out.extend_from_slice(rgb.get(i..i + 3).unwrap_or(&[0; 3]));
+            }
+        }
+
+        out
+    }
+}
+
+impl GifSink {
+    // Writes the pending frame, shown until frame `now`
+    fn flush(&mut self, now: u64, width: usize, height: usize) -> io::Result<()> {
+        let Some(picture) = self.pending.take() else {
+            return Ok(());
+        };
+
+        // Delays are rounded against the total so far, so they never drift
+        let end = now * 100 / FPS;
+        let delay = (end - self.written).min(u16::MAX as u64);
+        // GIFs can't show a frame for less than a hundredth of a second, it
+        // is skipped and the next frame makes up the time
+        if delay == 0 {
+            return Ok(());
+        }
+        self.written = end;
+
+        let mut frame = match picture {
+            Picture::Indexed(indices, palette) => gif::Frame {
+                width: width as u16,
+                height: height as u16,
+                buffer: Cow::Owned(indices),
+                palette: (palette != self.palette).then(|| palette.concat()),
+                ..gif::Frame::default()
+            },
+            // Quantised to a colour table of its own
+            Picture::Rgb(rgb) => gif::Frame::from_rgb_speed(width as u16, height as u16, &rgb, 10),
+        };
+        frame.delay = delay as u16;
+
+        self.encoder.write_frame(&frame).map_err(io::Error::other)
+    }
+}
+
+impl Y4mSink {
+    fn write(&mut self, picture: &Picture, sound: Sound) -> io::Result<()> {
+        let pixels: Vec<[u8; 3]> = match picture {
+            Picture::Indexed(indices, palette) => {
+                let yuv = palette.map(yuv);
+                indices.iter().map(|index| yuv[*index as usize]).collect()
+            }
+            Picture::Rgb(rgb) => rgb
+                .chunks_exact(3)
+                .map(|pixel| yuv([pixel[0], pixel[1], pixel[2]]))
+                .collect(),
+        };
+
+        self.video.write_all(b"FRAME\n")?;
+        let mut planes = [Vec::new(), Vec::new(), Vec::new()];
+        for pixel in pixels {
+            for (plane, value) in planes.iter_mut().zip(pixel) {
+                plane.push(value);
+            }
+        }
+        for plane in planes {
+            self.video.write_all(&plane)?;
+        }
+
+        // One frame worth of the beep
+        let mut samples = vec![0.0; SAMPLE_RATE as usize / FPS as usize];
+        self.beep.fill(sound, &mut samples);
+        for sample in samples {
+            let sample = (sample * i16::MAX as f32) as i16;
+            self.audio.write_all(&sample.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+// BT.601 full range
+fn yuv([r, g, b]: [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let u = 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
+    let v = 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
+    [y, u, v].map(|c| c.round().clamp(0.0, 255.0) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::DEFAULT_PALETTE;
+    use std::fs;
+
+    #[test]
+    fn test_gif() {
+        let path = std::env::temp_dir().join("chip8_recorder_test.gif");
+        let path = path.to_str().unwrap();
+
+        let mut framebuffer = Framebuffer::new();
+        let mut recorder = Recorder::start(
+            path,
+            &framebuffer,
+            &DEFAULT_PALETTE,
+            2,
+            BeepSettings::default(),
+        )
+        .unwrap();
+        for i in 0..60 {
+            if i % 20 == 0 {
+                framebuffer.draw_sprite(0, i / 10, 0, &[0xFF]);
+            }
+            recorder
+                .frame(&framebuffer, &DEFAULT_PALETTE, Sound::default())
+                .unwrap();
+        }
+        assert_eq!(recorder.frames(), 60);
+        recorder.finish().unwrap();
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(path).unwrap())
+            .unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (128, 64));
+
+        // Three different frames, a second in total
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        assert_eq!(delays.len(), 3);
+        assert_eq!(delays.iter().sum::<u16>(), 100);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_y4m() {
+        let path = std::env::temp_dir().join("chip8_recorder_test.y4m");
+        let path = path.to_str().unwrap();
+
+        let framebuffer = Framebuffer::new();
+        let mut recorder = Recorder::start(
+            path,
+            &framebuffer,
+            &DEFAULT_PALETTE,
+            1,
+            BeepSettings::default(),
+        )
+        .unwrap();
+        recorder
+            .frame(
+                &framebuffer,
+                &DEFAULT_PALETTE,
+                Sound {
+                    beeping: true,
+                    pattern: None,
+                },
+            )
+            .unwrap();
+        recorder.finish().unwrap();
+
+        let video = fs::read(path).unwrap();
+        let header = b"YUV4MPEG2 W64 H32 F60:1 Ip A1:1 C444\nFRAME\n";
+        assert!(video.starts_with(header));
+        assert_eq!(video.len(), header.len() + 64 * 32 * 3);
+
+        let audio = fs::read(format!("{}.pcm", path)).unwrap();
+        assert_eq!(audio.len(), 735 * 2);
+        assert!(audio.iter().any(|b| *b != 0));
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(format!("{}.pcm", path)).unwrap();
+    }
+
+    #[test]
+    fn test_rgb_frames() {
+        let framebuffer = Framebuffer::new();
+        let grey = [0x80; 64 * 32 * 3];
+
+        let path = std::env::temp_dir().join("chip8_recorder_test_rgb.y4m");
+        let path = path.to_str().unwrap();
+        let mut recorder = Recorder::start(
+            path,
+            &framebuffer,
+            &DEFAULT_PALETTE,
+            2,
+            BeepSettings::default(),
+        )
+        .unwrap();
+        recorder.frame_rgb(&grey, 64, 32, Sound::default()).unwrap();
+        recorder.finish().unwrap();
+
+        let video = fs::read(path).unwrap();
+        let header = b"YUV4MPEG2 W128 H64 F60:1 Ip A1:1 C444\nFRAME\n";
+        let planes = &video[header.len()..];
+        assert_eq!(planes.len(), 128 * 64 * 3);
+        // Luma, then neutral chroma
+        assert_eq!(
+            [planes[0], planes[128 * 64], planes[128 * 64 * 2]],
+            [0x80; 3]
+        );
+        fs::remove_file(path).unwrap();
+        fs::remove_file(format!("{}.pcm", path)).unwrap();
+
+        let path = std::env::temp_dir().join("chip8_recorder_test_rgb.gif");
+        let path = path.to_str().unwrap();
+        let mut recorder = Recorder::start(
+            path,
+            &framebuffer,
+            &DEFAULT_PALETTE,
+            1,
+            BeepSettings::default(),
+        )
+        .unwrap();
+        for _ in 0..30 {
+            recorder.frame_rgb(&grey, 64, 32, Sound::default()).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(path).unwrap())
+            .unwrap();
+        let frame = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!(frame.delay, 50);
+        let palette = frame.palette.as_ref().unwrap();
+        assert_eq!(palette[frame.buffer[0] as usize * 3], 0x80);
+        assert!(decoder.read_next_frame().unwrap().is_none());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+/*
+Screenshots of the emulated screen, saved as PNG files named after the ROM and
+the time they were taken.
+*/
+
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::drivers::video_driver;
+use crate::framebuffer::Framebuffer;
+use crate::image;
+use crate::palette::Palette;
+
+// "<rom>-YYYYMMDD-HHMMSS.<extension>" in UTC, with a counter added if the file exists
+pub fn file_name(rom_name: &str, extension: &str) -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs());
+    let base = format!("{}-{}", rom_name, timestamp(secs));
+
+    let mut name = format!("{}.{}", base, extension);
+    let mut n = 1;
+    while Path::new(&name).exists() {
+        n += 1;
+        name = format!("{}-{}.{}", base, n, extension);
+    }
+    name
+}
+
+fn timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Days since 1970-01-01 to a civil date, from Howard Hinnant's algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+// Saves the framebuffer, with each emulated pixel `scale` pixels wide
+pub fn save(
+    framebuffer: &Framebuffer,
+    palette: &Palette,
+    scale: usize,
+    path: &str,
+) -> io::Result<()> {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let scale = scale.max(1);
+    let frame = video_driver::render(framebuffer, palette);
+    let scaled = image::scale(&frame, width, height, scale);
+
+    image::write_png(path, width * scale, height * scale, &scaled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(0), "19700101-000000");
+        assert_eq!(timestamp(951_782_400 + 3_723), "20000229-010203");
+        assert_eq!(timestamp(1_792_368_000), "20261019-000000");
+    }
+}
@@ -0,0 +1,360 @@
+/*
+Settings files.
+
+Settings are read from a global file in the config directory, then from
+per-ROM files, then from the command line, each layer overriding the ones
+before it:
+
+    ~/.config/chip8/config.toml
+    ~/.config/chip8/roms/<rom name>.toml
+    <rom name>.toml next to the ROM
+
+Every key is optional:
+
+    platform = "schip"
+
+    [quirks]
+    shift = false
+
+    [timing]
+    tickrate = 30
+
+    [display]
+    theme = "amber"
+    palette = "000000,ffffff,aaaaaa,555555"
+    fg = "ffb000"
+    bg = "1a0f00"
+    scale = 12
+    integer-scale = true
+    fullscreen = false
+    show-fps = true
+    filters = "scanlines,bloom"
+    blend = 2
+    phosphor = 0.5
+
+    [audio]
+    enabled = true
+    frequency = 440.0
+    volume = 0.5
+    waveform = "triangle"
+
+    [keys]
+    5 = "Up"
+    8 = "Down"
+*/
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, ConfigFlags, Platform};
+use crate::drivers::audio_driver::Waveform;
+use crate::drivers::input_driver::parse_keypad_key;
+use crate::filters;
+use crate::palette;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub platform: Option<String>,
+    pub quirks: BTreeMap<String, bool>,
+    pub timing: TimingSettings,
+    pub display: DisplaySettings,
+    pub audio: AudioSettings,
+    pub keys: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TimingSettings {
+    pub tickrate: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DisplaySettings {
+    pub theme: Option<String>,
+    pub palette: Option<String>,
+    pub fg: Option<String>,
+    pub bg: Option<String>,
+    pub scale: Option<u32>,
+    pub integer_scale: Option<bool>,
+    pub fullscreen: Option<bool>,
+    pub show_fps: Option<bool>,
+    pub filters: Option<String>,
+    pub blend: Option<usize>,
+    pub phosphor: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AudioSettings {
+    pub enabled: Option<bool>,
+    pub frequency: Option<f32>,
+    pub volume: Option<f32>,
+    pub waveform: Option<String>,
+}
+
+impl Settings {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let settings: Settings = toml::from_str(text).map_err(|e| e.to_string())?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    // A missing file is an empty layer
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Settings::parse(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Settings::default()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    // The global settings, then the ROM's profile and per-ROM files from the
+    // config directory and next to the ROM
+    pub fn load_for_rom(
+        config_dir: Option<&Path>,
+        global: Option<&Path>,
+        profile: Settings,
+        rom_name: &str,
+        rom_path: Option<&Path>,
+    ) -> Result<Self, String> {
+        let mut settings = match global {
+            Some(file) => Settings::load(file)?,
+            None => Settings::default(),
+        };
+        settings = settings.merge(profile);
+        for file in rom_files(config_dir, rom_name, rom_path) {
+            settings = settings.merge(Settings::load(&file)?);
+        }
+        Ok(settings)
+    }
+
+    // Values set in `over` replace ours
+    pub fn merge(mut self, over: Settings) -> Self {
+        self.platform = over.platform.or(self.platform);
+        self.quirks.extend(over.quirks);
+        self.timing.tickrate = over.timing.tickrate.or(self.timing.tickrate);
+
+        let (display, over_display) = (&mut self.display, over.display);
+        // Blending and phosphor decay don't combine, the later choice wins
+        if over_display.blend.is_some() || over_display.phosphor.is_some() {
+            display.blend = None;
+            display.phosphor = None;
+        }
+        display.theme = over_display.theme.or(display.theme.take());
+        display.palette = over_display.palette.or(display.palette.take());
+        display.fg = over_display.fg.or(display.fg.take());
+        display.bg = over_display.bg.or(display.bg.take());
+        display.scale = over_display.scale.or(display.scale);
+        display.integer_scale = over_display.integer_scale.or(display.integer_scale);
+        display.fullscreen = over_display.fullscreen.or(display.fullscreen);
+        display.show_fps = over_display.show_fps.or(display.show_fps);
+        display.filters = over_display.filters.or(display.filters.take());
+        display.blend = over_display.blend.or(display.blend);
+        display.phosphor = over_display.phosphor.or(display.phosphor);
+
+        let (audio, over_audio) = (&mut self.audio, over.audio);
+        audio.enabled = over_audio.enabled.or(audio.enabled);
+        audio.frequency = over_audio.frequency.or(audio.frequency);
+        audio.volume = over_audio.volume.or(audio.volume);
+        audio.waveform = over_audio.waveform.or(audio.waveform.take());
+
+        self.keys.extend(over.keys);
+        self
+    }
+
+    // Platform, quirks and instructions per frame to run with
+    pub fn machine(&self) -> Result<(Platform, Config, usize), String> {
+        let platform = self
+            .platform
+            .as_deref()
+            .map_or(Ok(Platform::default()), Platform::parse)?;
+
+        let mut config = platform.config();
+        for (quirk, on) in &self.quirks {
+            config.set_flag(ConfigFlags::parse(quirk)?, *on);
+        }
+        let tickrate = self.timing.tickrate.unwrap_or(platform.tickrate()).max(1);
+        Ok((platform, config, tickrate))
+    }
+
+    // Errors name the offending key
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(platform) = &self.platform {
+            Platform::parse(platform).map_err(at("platform"))?;
+        }
+        for name in self.quirks.keys() {
+            let key = format!("quirks.{}", name);
+            ConfigFlags::parse(name).map_err(at(&key))?;
+        }
+        if self.timing.tickrate == Some(0) {
+            return Err("timing.tickrate: must be at least 1".to_string());
+        }
+
+        let display = &self.display;
+        if let Some(theme) = &display.theme {
+            palette::theme(theme).ok_or_else(|| {
+                format!(
+                    "display.theme: unknown theme '{}', expected one of {}",
+                    theme,
+                    palette::theme_names().join(", ")
+                )
+            })?;
+        }
+        if let Some(colours) = &display.palette {
+            palette::parse_palette(colours).map_err(at("display.palette"))?;
+        }
+        if let Some(fg) = &display.fg {
+            palette::parse_colour(fg).map_err(at("display.fg"))?;
+        }
+        if let Some(bg) = &display.bg {
+            palette::parse_colour(bg).map_err(at("display.bg"))?;
+        }
+        if display.scale == Some(0) {
+            return Err("display.scale: must be at least 1".to_string());
+        }
+        if let Some(names) = &display.filters {
+            filters::parse_filters(names).map_err(at("display.filters"))?;
+        }
+        if display.blend == Some(0) {
+            return Err("display.blend: must be at least 1".to_string());
+        }
+        if display.blend.is_some() && display.phosphor.is_some() {
+            return Err("display.blend: can't be combined with display.phosphor".to_string());
+        }
+        if display
+            .phosphor
+            .is_some_and(|decay| !(0.0..=1.0).contains(&decay))
+        {
+            return Err("display.phosphor: must be between 0 and 1".to_string());
+        }
+
+        let audio = &self.audio;
+        if audio.frequency.is_some_and(|frequency| frequency <= 0.0) {
+            return Err("audio.frequency: must be above 0".to_string());
+        }
+        if audio
+            .volume
+            .is_some_and(|volume| !(0.0..=1.0).contains(&volume))
+        {
+            return Err("audio.volume: must be between 0 and 1".to_string());
+        }
+        if let Some(waveform) = &audio.waveform {
+            Waveform::parse(waveform).map_err(at("audio.waveform"))?;
+        }
+
+        for (keypad, name) in &self.keys {
+            let key = format!("keys.{}", keypad);
+            parse_keypad_key(keypad).map_err(at(&key))?;
+            if name.trim().is_empty() {
+                return Err(format!("{}: missing key name", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Prefixes an error with the key it's about
+fn at(key: &str) -> impl Fn(String) -> String + '_ {
+    move |e| format!("{}: {}", key, e)
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("chip8"))
+}
+
+// Per-ROM files, in the order they apply
+pub fn rom_files(
+    config_dir: Option<&Path>,
+    rom_name: &str,
+    rom_path: Option<&Path>,
+) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Some(dir) = config_dir {
+        files.push(dir.join("roms").join(format!("{}.toml", rom_name)));
+    }
+    if let Some(path) = rom_path {
+        files.push(path.with_extension("toml"));
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let settings = Settings::parse(
+            r#"
+            platform = "schip"
+            [quirks]
+            shift = false
+            [display]
+            theme = "amber"
+            integer-scale = true
+            [audio]
+            volume = 0.5
+            [keys]
+            5 = "Up"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.platform.as_deref(), Some("schip"));
+        assert!(!settings.quirks["shift"]);
+        assert_eq!(settings.display.theme.as_deref(), Some("amber"));
+        assert_eq!(settings.display.integer_scale, Some(true));
+        assert_eq!(settings.display.scale, None);
+        assert_eq!(settings.audio.volume, Some(0.5));
+        assert_eq!(settings.keys["5"], "Up");
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        let error = |text: &str| Settings::parse(text).unwrap_err();
+
+        assert!(error("[display]\ntheme = \"neon\"").starts_with("display.theme:"));
+        assert!(error("[audio]\nvolume = 2.0").starts_with("audio.volume:"));
+        assert!(error("[quirks]\nwrap = true").starts_with("quirks.wrap:"));
+        assert!(error("[keys]\n10 = \"Up\"").starts_with("keys.10:"));
+        assert!(error("[display]\nfg = \"red\"").starts_with("display.fg:"));
+        // Unknown keys and wrong types are reported by the parser
+        assert!(error("[display]\nzoom = 2").contains("zoom"));
+        assert!(error("[timing]\ntickrate = \"fast\"").contains("tickrate"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let global = Settings::parse(
+            "platform = \"schip\"\n[display]\ntheme = \"amber\"\nphosphor = 0.5\n[keys]\n5 = \"Up\"",
+        )
+        .unwrap();
+        let rom = Settings::parse("[display]\nblend = 2\n[keys]\n8 = \"Down\"").unwrap();
+        let mut cli = Settings::default();
+        cli.display.theme = Some("lcd".to_string());
+
+        let settings = global.merge(rom).merge(cli);
+        assert_eq!(settings.platform.as_deref(), Some("schip"));
+        assert_eq!(settings.display.theme.as_deref(), Some("lcd"));
+        assert_eq!(settings.display.blend, Some(2));
+        assert_eq!(settings.display.phosphor, None);
+        assert_eq!(settings.keys.len(), 2);
+        settings.validate().unwrap();
+    }
+
+    #[test]
+    fn test_machine() {
+        let settings = Settings::parse("platform = \"schip\"\n[quirks]\nshift = false").unwrap();
+        let (platform, config, tickrate) = settings.machine().unwrap();
+        assert_eq!(platform, Platform::SuperChip);
+        assert!(!config.flag_set(ConfigFlags::Shift));
+        assert!(config.flag_set(ConfigFlags::JumpWithOffset));
+        assert_eq!(tickrate, 30);
+
+        assert_eq!(Settings::default().machine().unwrap().0, Platform::Chip8);
+    }
+}